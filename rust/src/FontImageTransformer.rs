@@ -1,28 +1,262 @@
-use std::borrow::Borrow;
-use image::{DynamicImage, imageops, GenericImageView, ImageBuffer, Rgba, RgbImage, SubImage, RgbaImage, Pixel, GenericImage};
-use rusttype::{Point, Font, GlyphId, GlyphIter, Scale, Glyph, Rect, PositionedGlyph};
-use std::cmp;
-use std::fmt::format;
-use std::ops::Index;
-use std::sync::{Arc, Mutex};
+use image::{DynamicImage, imageops, GenericImageView, ImageBuffer, LumaA, Rgba, RgbaImage, Pixel};
+use rusttype::{Point, Font, GlyphId, Scale, ScaledGlyph, PositionedGlyph};
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
 use std::time::Instant;
+use wasm_bindgen::prelude::*;
+use js_sys::Uint8Array;
+
+use crate::atlas::GlyphAtlas;
+use crate::blocks::{self, BlockMode};
+use crate::contact_sheet::contact_sheet;
+use crate::dedup::{self, GlyphKey, GlyphMask, GlyphMerge};
+use crate::fonts::FontStack;
+use crate::colour::ColourSpace;
+use crate::dither::{self, Dither, ErrorGrid};
+use crate::edges::{self, EdgeDescriptor};
+use crate::kernel::{self, PackedTile, PackedTileF32};
+use crate::palette::PaletteRegistry;
+use crate::preprocess::{self, Stage};
+use crate::ramp;
+use crate::style::{GlyphStyle, Rasterise, SyntheticGlyph};
+use crate::text::{self, TextCell};
+use crate::variant::GlyphVariant;
+
+
+/// How much the context around a cell counts when the comparison window is
+/// larger than the cell. Pixels inside the cell always have weight 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum WindowMask {
+    #[serde(rename = "flat")]
+    Flat,
+    #[serde(rename = "gaussian")]
+    Gaussian,
+}
+
+/// What to do with fonts whose glyphs don't share one advance width.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum ProportionalFonts {
+    /// Centre each glyph in a cell as wide as the widest advance.
+    #[serde(rename = "centre")]
+    Centre,
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    ProportionalFont { min_advance: f32, max_advance: f32 },
+    /// No font of the stack has any of the characters asked for.
+    NoCharacters,
+    /// `glyph_start..=glyph_end` holds none of the primary font's glyphs.
+    EmptyGlyphRange { start: u16, end: u16, glyphs: u16 },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::ProportionalFont { min_advance, max_advance } => write!(
+                f,
+                "Font is not monospaced, advances range from {:.1} to {:.1} pixels",
+                min_advance, max_advance
+            ),
+            RenderError::NoCharacters => write!(f, "No font has any of the characters"),
+            RenderError::EmptyGlyphRange { start, end, glyphs } => write!(
+                f,
+                "Glyph range {}..={} is empty, the font has glyphs 0..={}",
+                start, end, glyphs.saturating_sub(1)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Settings for a single render. Everything that used to be hardcoded in
+/// `start_processing` lives here; the defaults reproduce the old behaviour.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderOptions {
+    pub scale_x: f32,
+    pub scale_y: f32,
+    /// First glyph id to render.
+    pub glyph_start: u16,
+    /// Last glyph id to render, defaults to the font's glyph count.
+    pub glyph_end: Option<u16>,
+    /// Characters to render instead of the glyph range, each from the first
+    /// font of the stack that has it.
+    pub charset: Option<String>,
+    pub proportional: ProportionalFonts,
+    /// Sub-pixel offsets tried per axis. Each glyph is also rendered shifted
+    /// by `i / subpixel_offsets` pixels, `1` only uses the cell origin.
+    pub subpixel_offsets: u32,
+    /// Comparison window size relative to the output cell, e.g. `1.5` also
+    /// compares a band of context around each cell.
+    pub window_scale: f32,
+    pub window_mask: WindowMask,
+    /// Distance between cells, defaults to the cell size.
+    pub stride_x: Option<u32>,
+    pub stride_y: Option<u32>,
+    /// Blend between intensity (0) and edge orientation (1) matching.
+    pub edge_weight: f32,
+    pub dither: Dither,
+    /// Scales the diffused error, or the Bayer threshold spread (1.0 spreads
+    /// thresholds over 64 levels).
+    pub dither_strength: f32,
+    /// Space tiles and glyph renders are converted to before scoring.
+    /// Dithering stays in sRGB: the error is measured between sRGB means
+    /// and added to the tile before it is converted.
+    pub colour_space: ColourSpace,
+    /// Gamma glyph coverage is composited with. `1.0` blends the sRGB
+    /// values directly, around `2.2` blends in linear light.
+    pub glyph_gamma: f32,
+    /// Anti-aliased glyph edges; off rounds coverage to fully on or off.
+    pub glyph_antialias: bool,
+    /// Stages applied to the image, in order, before it is tiled.
+    pub preprocess: Vec<Stage>,
+    /// Use the image's alpha: cells whose mean alpha is below
+    /// `alpha_threshold` are left transparent and the other cells are
+    /// scored with each pixel weighted by its alpha.
+    pub transparency: bool,
+    /// Mean cell alpha (`0..1`) below which a cell is left empty.
+    pub alpha_threshold: f32,
+    /// Resample the image so the cell grid keeps the source aspect ratio
+    /// when shown with the font's advance width and line height.
+    pub aspect_correction: bool,
+    /// Displayed cell width / height, overrides the font metrics (e.g.
+    /// `0.5` for a typical terminal).
+    pub cell_aspect: Option<f32>,
+    /// Also leave out glyphs whose coverage is within this mean per-pixel
+    /// distance (`0..1`) of an earlier glyph. Exact duplicates always are.
+    pub prune_distance: f32,
+    /// Turned / mirrored copies of every glyph added as extra candidates,
+    /// for mosaics that don't need to stay readable.
+    pub glyph_variants: Vec<GlyphVariant>,
+    /// Styles every glyph is also rendered in. Taken from the style's font
+    /// when the stack has one, synthesised from the regular outline
+    /// otherwise.
+    pub glyph_styles: Vec<GlyphStyle>,
+    /// Stroke width synthetic bold adds, as a fraction of the font size.
+    pub embolden: f32,
+    /// Horizontal shear of synthetic italics (tangent of the slant).
+    pub oblique: f32,
+    /// Draw block characters with colours taken from the image instead of
+    /// matching glyphs. The cell is `scale_y` pixels high and `cell_aspect`
+    /// (default `0.5`) wide; fonts and palettes are not used.
+    pub block_mode: Option<BlockMode>,
+    /// Luminance (`0..1`) above which a Braille dot is raised.
+    pub braille_threshold: f32,
+    /// Raise Braille dots for the dark pixels instead, for dark text on a
+    /// light background.
+    pub braille_invert: bool,
+    /// Draw each cell's Braille dots in the mean colour of the pixels under
+    /// them, rather than plain white (black with `braille_invert`).
+    pub braille_colour: bool,
+    /// Pick each cell's character from this ramp (sparsest first, e.g.
+    /// `" .:-=+*#%@"`) by the cell's brightness instead of matching glyphs.
+    pub ramp: Option<String>,
+    /// Sort the ramp by each character's ink in the font and map brightness
+    /// to ink rather than to evenly spaced steps.
+    pub ramp_auto: bool,
+    /// Dense characters for dark cells, drawn dark on white.
+    pub ramp_invert: bool,
+    /// Draw each character in its cell's mean colour instead of white (black
+    /// with `ramp_invert`).
+    pub ramp_colour: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            scale_x: 18.0,
+            scale_y: 18.0,
+            glyph_start: 36,
+            glyph_end: None,
+            charset: None,
+            proportional: ProportionalFonts::Centre,
+            subpixel_offsets: 1,
+            window_scale: 1.0,
+            window_mask: WindowMask::Gaussian,
+            stride_x: None,
+            stride_y: None,
+            edge_weight: 0.0,
+            dither: Dither::None,
+            dither_strength: 1.0,
+            colour_space: ColourSpace::Srgb,
+            glyph_gamma: 1.0,
+            glyph_antialias: true,
+            preprocess: Vec::new(),
+            transparency: false,
+            alpha_threshold: 0.5,
+            aspect_correction: false,
+            cell_aspect: None,
+            prune_distance: 0.0,
+            glyph_variants: Vec::new(),
+            glyph_styles: Vec::new(),
+            embolden: 0.04,
+            oblique: 0.2,
+            block_mode: None,
+            braille_threshold: 0.5,
+            braille_invert: false,
+            braille_colour: true,
+            ramp: None,
+            ramp_auto: false,
+            ramp_invert: false,
+            ramp_colour: false,
+        }
+    }
+}
 
 
 #[wasm_bindgen]
 pub struct FontImageTransformer {
     status_callback: Option<Box<dyn Fn(f64)>>,
     status: bool,
+    font_data: Vec<u8>,
+    /// Fonts tried in order for characters the primary font lacks.
+    fallback_fonts: Vec<Vec<u8>>,
+    /// Bold / italic cuts of the font, see `RenderOptions::glyph_styles`.
+    style_fonts: Vec<(GlyphStyle, Vec<u8>)>,
+    image: Option<DynamicImage>,
+    glyph_background: Vec<Rgba<u8>>,
+    glyph_colours: Vec<Rgba<u8>>,
+    options: RenderOptions,
+    /// Glyph renders of the last run, reused while their key matches.
+    atlas: Option<GlyphAtlas>,
+    output: Option<RgbaImage>,
+    /// The cells of the last block or glyph render, for
+    /// [`FontImageTransformer::output_text`].
+    text: Option<Vec<Vec<TextCell>>>,
+}
+
+impl Default for FontImageTransformer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
 impl FontImageTransformer {
     #[wasm_bindgen(constructor)]
     pub fn new() -> FontImageTransformer {
+        let (glyph_background, glyph_colours) = default_colours();
+
         FontImageTransformer {
             status_callback: None,
             status: false,
+            font_data: Vec::new(),
+            fallback_fonts: Vec::new(),
+            style_fonts: Vec::new(),
+            image: None,
+            glyph_background,
+            glyph_colours,
+            options: RenderOptions::default(),
+            atlas: None,
+            output: None,
+            text: None,
         }
     }
 
@@ -34,265 +268,197 @@ impl FontImageTransformer {
         self.status_callback = Some(callback);
     }
 
-    pub fn start_processing(&mut self) {
-        self.status = true;
-
-        let num_threads = 24;
-        ThreadPoolBuilder::new()
-            .num_threads(num_threads)
-            .build_global()
-            .unwrap();
-
-        let font_data = include_bytes!(font_path);
-        let font = Font::try_from_bytes(font_data).unwrap();
-        let image_name = image_path;
-        let mut img = image::open("./images/input/".to_owned() + image_name).unwrap();
-
-        let colour = (150, 0, 0);
-
-        // Desired font pixel height
-        let height: f32 = 12.4; // to get 80 chars across (fits most terminals); adjust as desired
-        let pixel_height = height.ceil() as usize;
-
-        let scale = Scale {
-            x: 18.0,
-            y: 18.0
-        };
-
-        let point = Point {
-            x: 0.0,
-            y: 0.0
-        };
-
-        let v_metrics = font.v_metrics(scale);
-
-        println!("Rendering glyphs from font.");
-        println!("scale.x: {}, scale.y: {}", scale.x, scale.y);
-        println!("point.x: {}, point.y: {}", point.x, point.y);
+    pub fn set_font(&mut self, font: Uint8Array) {
+        self.font_data = font.to_vec();
+    }
 
-        let mut glyphs = Vec::new();
-        let mut glyph_height: u32 = 0;
-        let mut glyph_width: u32 = 0;
+    /// Appends a font to the fallback chain, see `RenderOptions::charset`.
+    pub fn add_fallback_font(&mut self, font: Uint8Array) {
+        self.fallback_fonts.push(font.to_vec());
+    }
 
-        println!("glyph_width: {}, glyph_height: {}", glyph_width, glyph_height);
-        let mut rendered_glyphs: Vec<RgbaImage> = Vec::new();
+    pub fn clear_fallback_fonts(&mut self) {
+        self.fallback_fonts.clear();
+    }
 
-        let default_rect = Rect {
-            min: Point { x: 0.0, y: 0.0 },
-            max: Point { x: 0.0, y: 0.0 },
-        };
+    /// Sets the font for `style` ("bold", "italic" or "bold-italic"), used
+    /// instead of synthesising it.
+    pub fn set_style_font(&mut self, style: &str, font: Uint8Array) -> Result<(), JsValue> {
+        let style = GlyphStyle::from_name(style)
+            .filter(|&style| style != GlyphStyle::Regular)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown glyph style: {}", style)))?;
+        self.style_fonts.retain(|&(s, _)| s != style);
+        self.style_fonts.push((style, font.to_vec()));
+        Ok(())
+    }
 
-        // Colour pallet method
-        let mut glyph_colours: Vec<Rgba<u8>> = Vec::new();
-        let mut glyph_background: Vec<Rgba<u8>> = Vec::new();
-        let black = Rgba([0,0,0,255]);
-        let white = Rgba([255,255,255,255]);
-
-        colour_scheme_gray_scale(&mut glyph_background, 3);
-        glyph_background.push(white);
-        glyph_colours.push(black);
-        //colour_scheme_gradations(&mut glyph_background, 3);
-        //colour_scheme_gradations(&mut glyph_colours, 4);
-        colour_scheme_gray_scale(&mut glyph_colours, 7);
-        //colour_scheme_plotlogic(&mut glyph_colours);
-        //glyph_colours.push(black);
-
-        let start= 36; // = 36;
-        let end = font.glyph_count() as u16; // = 36+25; //
-
-        let glyph_background_size = "glyph-min";
-        let glyph_background_size = "glyph-max";
-
-        let mut max_height = 0;
-        let mut max_width = 0;
-        let interception = true;
-
-        // Get the max width, max height
-        for i in 0u16..font.glyph_count().try_into().unwrap() {
-            if i < start || i > end {
-                continue;
-            }
+    pub fn clear_style_fonts(&mut self) {
+        self.style_fonts.clear();
+    }
 
-            let glyphId = GlyphId(i);
+    pub fn set_image(&mut self, image: Uint8Array) -> Result<(), JsValue> {
+        let img = image::load_from_memory(&image.to_vec())
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.image = Some(img);
+        Ok(())
+    }
 
-            let scaled_glyph = font.glyph(glyphId).scaled(scale);
-            let height = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).height().ceil() as u32 + 1;
-            let width = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).width().ceil() as u32 + 1;
+    /// Colours are packed RGBA, four bytes per colour.
+    pub fn set_colours(&mut self, background: Uint8Array, foreground: Uint8Array) {
+        self.glyph_background = unpack_colours(&background.to_vec());
+        self.glyph_colours = unpack_colours(&foreground.to_vec());
+    }
 
-            max_width = std::cmp::max(width, max_width);
-            max_height = std::cmp::max(height, max_height);
-        }
+    /// Selects registered palettes by name, see `palette_names`.
+    pub fn set_palettes(&mut self, background: &str, foreground: &str) -> Result<(), JsValue> {
+        let registry = PaletteRegistry::new();
+        let to_js = |e: crate::palette::PaletteError| JsValue::from_str(&e.to_string());
+        self.glyph_background = registry.get(background).map_err(to_js)?.colours;
+        self.glyph_colours = registry.get(foreground).map_err(to_js)?.colours;
+        Ok(())
+    }
 
-        // Get
-        let mut counter = 0;
-        for i in 0u16..font.glyph_count().try_into().unwrap() {
+    /// Accepts a (partial) `RenderOptions` object, missing fields keep their
+    /// defaults.
+    pub fn set_options(&mut self, options: JsValue) -> Result<(), JsValue> {
+        self.options = serde_wasm_bindgen::from_value(options)?;
+        Ok(())
+    }
 
-            if i < start || i > end {
-                continue;
-            }
+    pub fn start_processing(&mut self) -> Result<(), JsValue> {
+        self.status = true;
+        let result = self.process();
+        self.status = false;
+        result
+    }
 
-            let glyphId = GlyphId(i);
+    /// The glyph atlas of the last render as PNG, to persist with
+    /// [`FontImageTransformer::atlas_index`].
+    pub fn atlas_png(&self) -> Result<Vec<u8>, JsValue> {
+        let atlas = self.atlas.as_ref().ok_or_else(|| JsValue::from_str("No glyph atlas yet."))?;
+        encode_png(&atlas.pack().0).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
 
-            let scaled_glyph = font.glyph(glyphId).scaled(scale);
-            let height = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).height().ceil() as u32 + 1;
-            let width = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).width().ceil() as u32 + 1;
+    pub fn atlas_index(&self) -> Option<String> {
+        self.atlas.as_ref().map(|atlas| atlas.pack().1)
+    }
 
-            if height == 0 || width == 0 {
-                continue;
-            }
+    /// Restores a saved atlas. It is only used while its key matches the
+    /// font, colours and options.
+    pub fn set_atlas(&mut self, image: Uint8Array, index: &str) -> Result<(), JsValue> {
+        let image = image::load_from_memory(&image.to_vec())
+            .map_err(|e| JsValue::from_str(&e.to_string()))?
+            .to_rgba8();
+        self.atlas = Some(GlyphAtlas::unpack(&image, index).ok_or_else(|| JsValue::from_str("Invalid glyph atlas."))?);
+        Ok(())
+    }
 
+    /// Debug contact sheet of every glyph render in the current atlas, as PNG.
+    pub fn contact_sheet_png(&self) -> Result<Vec<u8>, JsValue> {
+        let atlas = self.atlas.as_ref().ok_or_else(|| JsValue::from_str("No glyph atlas yet."))?;
+        encode_png(&contact_sheet(atlas, &self.font_stack()?)).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
 
-            let positioned_glyph = scaled_glyph.positioned(point);
+    /// Which glyphs the current atlas left out as duplicates, one
+    /// `glyph -> kept glyph (distance)` line each.
+    pub fn merge_report(&self) -> Option<String> {
+        let atlas = self.atlas.as_ref()?;
+        Some(dedup::report(&atlas.merged, &self.font_stack().ok()?.codepoints()))
+    }
 
-            println!("width: {}, height: {}", width, height);
-            glyph_width = std::cmp::max(glyph_width, width);
-            glyph_height = std::cmp::max(glyph_height, height);
+    /// The last render encoded as PNG.
+    pub fn output_png(&self) -> Result<Vec<u8>, JsValue> {
+        let out = self.output.as_ref()
+            .ok_or_else(|| JsValue::from_str("Nothing rendered yet."))?;
+        encode_png(out).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
 
+    /// The last block or glyph render as text, with ANSI colour and style
+    /// escapes when `ansi` is set.
+    pub fn output_text(&self, ansi: bool) -> Result<String, JsValue> {
+        let cells = self.text.as_ref()
+            .ok_or_else(|| JsValue::from_str("Ramp renders have no text."))?;
+        Ok(match ansi {
+            true => text::ansi_text(cells),
+            false => text::plain_text(cells),
+        })
+    }
 
-            for background_colour in &glyph_background {
+    /// The last block or glyph render as an HTML `<pre>` block.
+    pub fn output_html(&self) -> Result<String, JsValue> {
+        let cells = self.text.as_ref()
+            .ok_or_else(|| JsValue::from_str("Ramp renders have no text."))?;
+        Ok(text::html_text(cells))
+    }
 
-                let mut background;
-                if glyph_background_size == "glyph-min" {
-                    background = DynamicImage::new_rgba8(width, height).to_rgba8();
-                } else if glyph_background_size == "glyph-max" {
-                    background = DynamicImage::new_rgba8(max_width, max_height).to_rgba8();
-                } else {
-                    background = DynamicImage::new_rgba8(width, height).to_rgba8();
-                }
+    pub fn stop_processing(&mut self) {
+        self.status = false;
+    }
 
-                paint_background(&mut background, background_colour);
-                for glyph_colour in &glyph_colours {
-                    //if let Some(bb) = glyph.pixel_bounding_box() {
-                    let mut glyph = background.clone();
-                    paint_character(&mut glyph, glyph_colour, &positioned_glyph);
-                    // Could add rotation as well
-                    glyph.save(format!("./images/glyphs/{}.png", counter)).expect("TODO: panic message");
-                    counter += 1;
 
-                    glyph_width = glyph_width.max(glyph.width());
-                    glyph_height = glyph_height.max(glyph.height());
 
-                    rendered_glyphs.push(glyph);
+}
 
+impl FontImageTransformer {
+    /// Renders the image into `output`, see [`FontImageTransformer::start_processing`].
+    fn process(&mut self) -> Result<(), JsValue> {
+        let img = self.image.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded."))?;
+
+        if let Some(mode) = self.options.block_mode {
+            let callback = &self.status_callback;
+            let (out, cells) = blocks::render_blocks(img, mode, &self.options, &mut |completed, total| {
+                if let Some(callback) = callback {
+                    callback(completed as f64 / total as f64);
                 }
-            }
-
-            glyphs.push(positioned_glyph);
-
+            });
+            self.output = Some(out);
+            self.text = Some(text::block_text(&cells, mode));
+            return Ok(());
         }
 
+        self.text = None;
+        let fonts = self.font_stack()?;
 
-        println!("glyph_width: {}, glyph_height: {}", glyph_width, glyph_height);
-        //let mut rendered_glyphs : Vec<RgbaImage> = Vec::new();
-
-        println!("Rendering glyphs into bitmaps.");
-        for glyph in glyphs {
-            let mut image = DynamicImage::new_rgba8(glyph_width, glyph_height).to_rgba8();
-
-            if let Some(bb) = glyph.pixel_bounding_box() {
-                let mut image = DynamicImage::new_rgba8(glyph_width, glyph_height).to_rgba8();
-                let mut image = DynamicImage::new_rgba8(glyph_width, glyph_height).to_rgba8();
-
-                if let Some(bb) = glyph.pixel_bounding_box() {
-                    let o = |x, y, v| {
-                        image.put_pixel(
-                            // Offset the position by the glyph bounding box
-                            x as u32,
-                            y as u32,
-
-                            // Turn the coverage into an alpha value
-                            Rgba([255, 255, 255, (v * 255.0) as u8]),
-                        )
-                    };
-
-                    glyph.draw(o);
-                    //let path = format!("./export/{}.png", counter);
-                    //image.save(path).expect("TODO: panic message");
-
-                    rendered_glyphs.push(image);
+        if let Some(characters) = &self.options.ramp {
+            let callback = &self.status_callback;
+            let out = ramp::render_ramp(&fonts, img, characters, &self.options, &mut |completed, total| {
+                if let Some(callback) = callback {
+                    callback(completed as f64 / total as f64);
                 }
-            }
+            });
+            self.output = Some(out.map_err(|e| JsValue::from_str(&e.to_string()))?);
+            return Ok(());
         }
-        let (width, height) = img.dimensions();
-        let (kx, ky) = (glyph_width, glyph_height);
-        let (stride_x, stride_y) = (glyph_width as usize, glyph_height as usize);
-
-        let mut img_gray = img.grayscale();
-        let mut img_cp = img.clone();
-        let mut out: RgbaImage = ImageBuffer::new(width, height);
 
-        // 1. Filter Size
+        let key = GlyphAtlas::key(&fonts, &self.glyph_background, &self.glyph_colours, &self.options);
+        let atlas = match self.atlas.take() {
+            Some(atlas) if atlas.key == key => atlas,
+            _ => GlyphAtlas::build(key, &fonts, &self.glyph_background, &self.glyph_colours, &self.options)
+                .map_err(|e| JsValue::from_str(&e.to_string()))?,
+        };
 
-        let total_kernel_ops = ((width - kx) / stride_x as u32) * ((height - ky) / stride_y as u32);
-        let mut data = vec![];
-        for x in (0..width - kx).step_by(stride_x) {
-            for y in (0..height - ky).step_by(stride_y) {
-                let subimg = img.view(x, y, kx, ky);
-                let item = (subimg, x, y);
-                data.push(item);
+        let callback = &self.status_callback;
+        let (out, matches) = render_with_atlas(img, &atlas, &self.options, &mut |completed, total| {
+            if let Some(callback) = callback {
+                callback(completed as f64 / total as f64);
             }
-        }
-
-        let results = Arc::new(Mutex::new(Vec::with_capacity(data.len())));
-        let start_time = Instant::now(); // Record the start time before the loop starts
-
-        data.par_iter().for_each(|item| {
-            let subimg = &item.0;
-            let x = &item.1;
-            let y = &item.2;
-
-            let index = match_character(subimg, &rendered_glyphs);
-            let result = (index, x, y);
-            let mut results_guard = results.lock().unwrap();
-            results_guard.push(result);
-            print_eta(results_guard.len() as u32, total_kernel_ops, start_time);
         });
 
-        let results_guard = results.lock().unwrap();
-        for result in results_guard.iter() {
-            let index = result.0;
-            let x = result.1;
-            let y = result.2;
-
-            let character = &rendered_glyphs[index];
-            imageops::overlay(&mut out, character, *x as i64, *y as i64);
-        }
-
-        out.save("./images/output/".to_owned() + image_name).expect("TODO: panic message");
-
+        self.text = Some(text::atlas_text(&matches, &atlas, &fonts.codepoints()));
+        self.atlas = Some(atlas);
+        self.output = Some(out);
+        Ok(())
     }
 
-    pub fn stop_processing(&mut self) {
-        self.status = false;
-    }
-
-
-
-}
-
-
-fn print_opentype_layout(name: &str, table: &ttf_parser::opentype_layout::LayoutTable) {
-    println!("OpenType {}:", name);
-    println!("  Scripts:");
-    for script in table.scripts {
-        println!("    {}", script.tag);
-
-        if script.languages.is_empty() {
-            println!("      No languages");
-            continue;
-        }
-
-        println!("      Languages:");
-        for lang in script.languages {
-            println!("        {}", lang.tag);
+    /// The font followed by the fallback fonts, then the style fonts.
+    fn font_stack(&self) -> Result<FontStack, JsValue> {
+        let data = std::iter::once(&self.font_data).chain(&self.fallback_fonts).cloned().collect();
+        let mut fonts = FontStack::new(data).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        for (style, data) in &self.style_fonts {
+            fonts.add_style(*style, data.clone()).map_err(|e| JsValue::from_str(&e.to_string()))?;
         }
-    }
-
-    let mut features: Vec<_> = table.features.into_iter().map(|f| f.tag).collect();
-    features.dedup();
-    println!("  Features:");
-    for feature in features {
-        println!("    {}", feature);
+        Ok(fonts)
     }
 }
 
@@ -305,7 +471,7 @@ pub fn parse_image(
     foreground: Uint8Array,
     status_callback: &js_sys::Function,
 
-) {
+) -> Result<Vec<u8>, JsValue> {
     /*
            1. Preprocess the image
                - Greyscale
@@ -330,243 +496,1037 @@ pub fn parse_image(
                - ANN (tff, scale, backgrounds, colours)
                    - Train a large network, just do drop out on the glyphs that dont exist
         */
+    let fonts = FontStack::new(vec![font.to_vec()])
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let img = image::load_from_memory(&image.to_vec())
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    let glyph_background = unpack_colours(&background.to_vec());
+    let glyph_colours = unpack_colours(&foreground.to_vec());
+
+    let out = render(&fonts, &img, &glyph_background, &glyph_colours, &RenderOptions::default(), &mut |completed, total| {
+        let progress = completed as f64 / total as f64;
+        status_callback.call1(&JsValue::NULL, &JsValue::from(progress)).unwrap();
+    }).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    encode_png(&out).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+
+/// The `default-background` and `default-foreground` palettes, the colours
+/// `start_processing` uses until others are set.
+pub fn default_colours() -> (Vec<Rgba<u8>>, Vec<Rgba<u8>>) {
+    let registry = PaletteRegistry::new();
+    let glyph_background = registry.get("default-background").unwrap().colours;
+    let glyph_colours = registry.get("default-foreground").unwrap().colours;
+
+    (glyph_background, glyph_colours)
+}
+
+pub fn unpack_colours(data: &[u8]) -> Vec<Rgba<u8>> {
+    data.chunks_exact(4)
+        .map(|c| Rgba([c[0], c[1], c[2], c[3]]))
+        .collect()
+}
+
+pub fn encode_png(img: &RgbaImage) -> image::ImageResult<Vec<u8>> {
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut bytes, image::ImageOutputFormat::Png)?;
+    Ok(bytes.into_inner())
+}
+
+pub fn init_thread_pool() {
     let num_threads = 24;
-    ThreadPoolBuilder::new()
+    // Already initialised on a second call, which is fine.
+    let _ = ThreadPoolBuilder::new()
         .num_threads(num_threads)
-        .build_global()
-        .unwrap();
+        .build_global();
+}
+
+/// Cell geometry shared by every glyph, from the font's metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CellMetrics {
+    /// Widest advance in the glyph range.
+    pub advance: f32,
+    /// Ascent - descent + line gap.
+    pub line_height: f32,
+    /// Baseline offset from the top of the cell.
+    pub ascent: f32,
+    /// The cell in whole pixels.
+    pub width: u32,
+    pub height: u32,
+}
 
-    let font_data = include_bytes!(font_path);
-    let font = Font::try_from_bytes(font_data).unwrap();
-    let image_name = image_path;
-    let mut img = image::open("./images/input/".to_owned() + image_name).unwrap();
+impl CellMetrics {
+    /// Width / height of the cell as a text grid shows it.
+    pub fn aspect(&self) -> f32 {
+        self.advance / self.line_height
+    }
+}
 
-    let colour = (150, 0, 0);
+/// What one rendered glyph image shows.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GlyphRender {
+    /// Position of the font in the stack.
+    pub font: u8,
+    pub glyph: u16,
+    pub style: GlyphStyle,
+    pub variant: Option<GlyphVariant>,
+    /// Index into [`subpixel_points`].
+    pub subpixel: u32,
+    /// Both `None` for the white coverage masks.
+    pub background: Option<[u8; 4]>,
+    pub foreground: Option<[u8; 4]>,
+}
 
-    // Desired font pixel height
-    let height: f32 = 12.4; // to get 80 chars across (fits most terminals); adjust as desired
-    let pixel_height = height.ceil() as usize;
+impl GlyphRender {
+    pub fn key(&self) -> GlyphKey {
+        GlyphKey { font: self.font, glyph: self.glyph, style: self.style, variant: self.variant }
+    }
+}
 
-    let scale = Scale {
-        x: 18.0,
-        y: 18.0
+/// Ids of the glyphs a render uses, or an error if there are none.
+pub fn glyph_range(font: &Font, options: &RenderOptions) -> Result<std::ops::RangeInclusive<u16>, RenderError> {
+    let glyphs = font.glyph_count() as u16;
+    let end = options.glyph_end.unwrap_or(u16::MAX).min(glyphs.saturating_sub(1));
+    match glyphs > 0 && options.glyph_start <= end {
+        true => Ok(options.glyph_start..=end),
+        false => Err(RenderError::EmptyGlyphRange {
+            start: options.glyph_start,
+            end: options.glyph_end.unwrap_or(end),
+            glyphs,
+        }),
+    }
+}
+
+/// The (font, glyph id) pairs a render picks from: the charset resolved
+/// against the font stack, or else the primary font's glyph range.
+/// Characters no font has are left out, see [`FontStack::resolve_charset`].
+pub fn candidate_glyphs(fonts: &FontStack, options: &RenderOptions) -> Result<Vec<(u8, u16)>, RenderError> {
+    let Some(charset) = &options.charset else {
+        return Ok(glyph_range(fonts.primary(), options)?.map(|id| (0, id)).collect());
     };
 
-    let point = Point {
-        x: 0.0,
-        y: 0.0
+    let (resolved, _) = fonts.resolve_charset(charset);
+    Ok(resolved.into_iter().map(|(_, glyph)| glyph).collect())
+}
+
+/// Cell size from the primary font's vertical metrics and the widest
+/// advance among `glyphs` from it (from all of them when the primary font
+/// has none). Zero-width glyphs (combining marks) don't count towards
+/// telling proportional fonts apart.
+pub fn cell_metrics(fonts: &FontStack, glyphs: &[(u8, u16)], options: &RenderOptions) -> Result<CellMetrics, RenderError> {
+    let scale = Scale { x: options.scale_x, y: options.scale_y };
+    let scales = fonts.scales(scale);
+    let v_metrics = fonts.primary().v_metrics(scale);
+    let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap).max(1.0);
+
+    let sizing: Vec<(u8, u16)> = match glyphs.iter().any(|&(font, _)| font == 0) {
+        true => glyphs.iter().copied().filter(|&(font, _)| font == 0).collect(),
+        false => glyphs.to_vec(),
     };
+    let (min_advance, max_advance) = sizing.into_iter()
+        .map(|(font, i)| fonts.fonts()[font as usize].glyph(GlyphId(i)).scaled(scales[font as usize]).h_metrics().advance_width)
+        .filter(|&advance| advance > 0.0)
+        .fold((f32::MAX, 0.0f32), |(lo, hi), advance| (lo.min(advance), hi.max(advance)));
 
-    let v_metrics = font.v_metrics(scale);
+    if options.proportional == ProportionalFonts::Reject && max_advance - min_advance > 0.5 {
+        return Err(RenderError::ProportionalFont { min_advance, max_advance });
+    }
 
-    println!("Rendering glyphs from font.");
-    println!("scale.x: {}, scale.y: {}", scale.x, scale.y);
-    println!("point.x: {}, point.y: {}", point.x, point.y);
+    let advance = max_advance.max(1.0);
+    Ok(CellMetrics {
+        advance,
+        line_height,
+        ascent: v_metrics.ascent,
+        width: advance.ceil() as u32,
+        height: line_height.ceil() as u32,
+    })
+}
 
-    let mut glyphs = Vec::new();
-    let mut glyph_height: u32 = 0;
-    let mut glyph_width: u32 = 0;
+/// Where glyph `i` of `font` sits in `cell`: the scaled glyph, its pen
+/// position and baseline. `scales` are the stack's [`FontStack::scales`].
+pub fn layout_glyph<'a>(fonts: &'a FontStack, scales: &[Scale], cell: &CellMetrics, font: u8, i: u16) -> (ScaledGlyph<'a>, f32, f32) {
+    let face = &fonts.fonts()[font as usize];
+    let mut glyph_scale = scales[font as usize];
+    // Glyphs of other fonts wider than the cell are narrowed to fit it.
+    let advance = face.glyph(GlyphId(i)).scaled(glyph_scale).h_metrics().advance_width;
+    if font != 0 && advance > cell.advance {
+        glyph_scale.x *= cell.advance / advance;
+    }
+    let baseline = match font {
+        0 => cell.ascent,
+        _ => face.v_metrics(glyph_scale).ascent,
+    };
 
-    println!("glyph_width: {}, glyph_height: {}", glyph_width, glyph_height);
-    let mut rendered_glyphs: Vec<RgbaImage> = Vec::new();
+    let scaled_glyph = face.glyph(GlyphId(i)).scaled(glyph_scale);
+    // Narrower glyphs of proportional fonts sit in the middle of the cell.
+    let pen_x = (cell.advance - scaled_glyph.h_metrics().advance_width) / 2.0;
+    (scaled_glyph, pen_x, baseline)
+}
 
-    let default_rect = Rect {
-        min: Point { x: 0.0, y: 0.0 },
-        max: Point { x: 0.0, y: 0.0 },
+/// Glyph renders, what each shows, the cell they share and the glyphs left
+/// out as duplicates.
+pub type GlyphRenders = (Vec<RgbaImage>, Vec<GlyphRender>, CellMetrics, Vec<GlyphMerge>);
+
+/// A glyph placed in the cell and its bounding box origin.
+type Placed<'a> = (Box<dyn Rasterise + 'a>, i32, i32);
+
+/// Renders every candidate glyph once per background and foreground colour,
+/// on a common baseline in cells of the primary font's advance by its line
+/// height, followed by one white coverage mask per glyph. All renders share
+/// the same size. Glyphs that duplicate an earlier one are left out and
+/// returned as merges.
+pub fn render_glyphs(
+    fonts: &FontStack,
+    glyph_background: &[Rgba<u8>],
+    glyph_colours: &[Rgba<u8>],
+    options: &RenderOptions,
+) -> Result<GlyphRenders, RenderError> {
+    let scale = Scale {
+        x: options.scale_x,
+        y: options.scale_y
     };
 
-    // Colour pallet method
-    let mut glyph_colours: Vec<Rgba<u8>> = Vec::new();
-    let mut glyph_background: Vec<Rgba<u8>> = Vec::new();
-    let black = Rgba([0,0,0,255]);
-    let white = Rgba([255,255,255,255]);
+    let points = subpixel_points(options.subpixel_offsets);
+    let coverage = Coverage::from_options(options);
+    let candidates = candidate_glyphs(fonts, options)?;
+    if candidates.is_empty() {
+        return Err(RenderError::NoCharacters);
+    }
+    let cell = cell_metrics(fonts, &candidates, options)?;
+    let scales = fonts.scales(scale);
+
+    let layout = |font: u8, i: u16| layout_glyph(fonts, &scales, &cell, font, i);
+
+    let styles: Vec<GlyphStyle> = std::iter::once(GlyphStyle::Regular)
+        .chain(options.glyph_styles.iter().copied().filter(|&style| style != GlyphStyle::Regular))
+        .collect();
+    // Style fonts are looked up by character.
+    let codepoints = match styles.iter().any(|&style| fonts.style_font(style).is_some()) {
+        true => fonts.codepoints(),
+        false => Vec::new(),
+    };
+    let embolden = options.embolden * options.scale_y;
+    let pivot_y = cell.height as f32 / 2.0;
+
+    // Every glyph in every style at every sub-pixel offset, with its bounding
+    // box origin (coverage is drawn relative to it).
+    let mut placed: Vec<(u8, u16, GlyphStyle, Vec<Placed>)> = Vec::new();
+    for (font, i) in candidates {
+        for &style in &styles {
+            let cut = fonts.style_font(style).and_then(|style_font| {
+                let c = *codepoints.get(font as usize)?.get(&i)?;
+                let id = fonts.fonts()[style_font as usize].glyph(c).id().0;
+                (id != 0).then_some((style_font, id))
+            });
+            // The regular glyph, the style font's glyph, or a synthesised one.
+            let ((font, i), synthetic) = match (style, cut) {
+                (GlyphStyle::Regular, _) => ((font, i), None),
+                (_, Some(cut)) => (cut, None),
+                (style, None) => ((font, i), Some(style)),
+            };
+
+            let (scaled_glyph, pen_x, baseline) = layout(font, i);
+            let positions = points.iter().map(|point| {
+                let position = Point { x: pen_x + point.x, y: baseline + point.y };
+                let glyph: Box<dyn Rasterise> = match synthetic {
+                    None => Box::new(scaled_glyph.clone().positioned(position)),
+                    Some(style) => Box::new(SyntheticGlyph::new(
+                        &scaled_glyph,
+                        position,
+                        if style.is_bold() { embolden } else { 0.0 },
+                        if style.is_italic() { options.oblique } else { 0.0 },
+                        pivot_y,
+                    )),
+                };
+                let (dx, dy) = glyph.pixel_bounding_box().map_or((0, 0), |bb| (bb.min.x, bb.min.y));
+                (glyph, dx, dy)
+            }).collect();
+            placed.push((font, i, style, positions));
+        }
+    }
 
-    colour_scheme_gray_scale(&mut glyph_background, 3);
-    glyph_background.push(white);
-    glyph_colours.push(black);
-    //colour_scheme_gradations(&mut glyph_background, 3);
-    //colour_scheme_gradations(&mut glyph_colours, 4);
-    colour_scheme_gray_scale(&mut glyph_colours, 7);
-    //colour_scheme_plotlogic(&mut glyph_colours);
-    //glyph_colours.push(black);
+    // The glyph as drawn, then each requested variant of it.
+    let variants: Vec<Option<GlyphVariant>> = std::iter::once(None)
+        .chain(options.glyph_variants.iter().copied().map(Some))
+        .collect();
+
+    let mut masks = Vec::with_capacity(placed.len() * variants.len());
+    for (font, i, style, positions) in &placed {
+        for &variant in &variants {
+            let mut mask = GlyphMask {
+                key: GlyphKey { font: *font, glyph: *i, style: *style, variant },
+                coverage: Vec::new(),
+                drawn: Vec::new(),
+            };
+            for (glyph, dx, dy) in positions {
+                let cells = coverage_mask(&**glyph, *dx, *dy, cell, coverage);
+                let cells = match variant {
+                    None => cells,
+                    Some(variant) => variant.apply(&cells),
+                };
+                for pixel in cells.pixels() {
+                    mask.coverage.push(pixel[0]);
+                    mask.drawn.push(pixel[1] > 0.0);
+                }
+            }
+            masks.push(mask);
+        }
+    }
+    let (keep, merges) = dedup::distinct(&masks, options.prune_distance);
 
-    let start= 36; // = 36;
-    let end = font.glyph_count() as u16; // = 36+25; //
+    let mut glyphs = Vec::new();
+    let mut rendered_glyphs: Vec<RgbaImage> = Vec::new();
+    let mut renders = Vec::new();
 
-    let glyph_background_size = "glyph-min";
-    let glyph_background_size = "glyph-max";
+    let units = placed.iter().flat_map(|placed| variants.iter().map(move |&variant| (placed, variant)));
+    for ((&(font, i, style, ref positions), variant), _) in units.zip(keep).filter(|(_, keep)| *keep) {
+        for (subpixel, &(ref positioned_glyph, dx, dy)) in positions.iter().enumerate() {
+            for background_colour in glyph_background {
+                let mut background = RgbaImage::new(cell.width, cell.height);
+                paint_background(&mut background, background_colour);
 
-    let mut max_height = 0;
-    let mut max_width = 0;
-    let interception = true;
+                for glyph_colour in glyph_colours {
+                    let mut glyph = background.clone();
+                    paint_character_at(&mut glyph, glyph_colour, &**positioned_glyph, dx, dy, coverage);
+                    if let Some(variant) = variant {
+                        glyph = variant.apply(&glyph);
+                    }
+                    rendered_glyphs.push(glyph);
+                    renders.push(GlyphRender {
+                        font,
+                        glyph: i,
+                        style,
+                        variant,
+                        subpixel: subpixel as u32,
+                        background: Some(background_colour.0),
+                        foreground: Some(glyph_colour.0),
+                    });
+                }
+            }
 
-    // Get the max width, max height
-    for i in 0u16..font.glyph_count().try_into().unwrap() {
-        if i < start || i > end {
-            continue;
+            glyphs.push((positioned_glyph, dx, dy, font, i, style, variant, subpixel as u32));
         }
+    }
 
-        let glyphId = GlyphId(i);
+    for (glyph, dx, dy, font, id, style, variant, subpixel) in glyphs {
+        if glyph.pixel_bounding_box().is_some() {
+            let mut image = RgbaImage::new(cell.width, cell.height);
+
+            let mut o = |x: u32, y: u32, v: f32| {
+                // Offset the position by the glyph bounding box
+                let (x, y) = (x as i32 + dx, y as i32 + dy);
+                if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+                    // Turn the coverage into an alpha value
+                    image.put_pixel(x as u32, y as u32, Rgba([255, 255, 255, (coverage.alpha(v) * 255.0) as u8]));
+                }
+            };
 
-        let scaled_glyph = font.glyph(glyphId).scaled(scale);
-        let height = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).height().ceil() as u32 + 1;
-        let width = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).width().ceil() as u32 + 1;
+            glyph.draw(&mut o);
 
-        max_width = std::cmp::max(width, max_width);
-        max_height = std::cmp::max(height, max_height);
+            if let Some(variant) = variant {
+                image = variant.apply(&image);
+            }
+            rendered_glyphs.push(image);
+            renders.push(GlyphRender { font, glyph: id, style, variant, subpixel, background: None, foreground: None });
+        }
     }
 
-    // Get
-    let mut counter = 0;
-    for i in 0u16..font.glyph_count().try_into().unwrap() {
+    Ok((rendered_glyphs, renders, cell, merges))
+}
 
-        if i < start || i > end {
-            continue;
+/// Matches every cell of `img` against the rendered glyphs and composes the
+/// output. `progress` is called with (completed rows, total rows).
+pub fn render(
+    fonts: &FontStack,
+    img: &DynamicImage,
+    glyph_background: &[Rgba<u8>],
+    glyph_colours: &[Rgba<u8>],
+    options: &RenderOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> Result<RgbaImage, RenderError> {
+    if let Some(mode) = options.block_mode {
+        return Ok(blocks::render_blocks(img, mode, options, progress).0);
+    }
+    if let Some(characters) = &options.ramp {
+        return ramp::render_ramp(fonts, img, characters, options, progress);
+    }
+    let atlas = GlyphAtlas::build(String::new(), fonts, glyph_background, glyph_colours, options)?;
+    Ok(render_with_atlas(img, &atlas, options, progress).0)
+}
+
+/// [`render`] with glyphs that have already been rendered. Also returns
+/// the index into `atlas.glyphs` picked for each cell, row by row, `None`
+/// for empty cells (see [`crate::text`] to write them out).
+pub fn render_with_atlas(
+    img: &DynamicImage,
+    atlas: &GlyphAtlas,
+    options: &RenderOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> (RgbaImage, Vec<Vec<Option<usize>>>) {
+    init_thread_pool();
+
+    let preprocessed;
+    let img = match options.preprocess.as_slice() {
+        [] => img,
+        stages => {
+            preprocessed = preprocess::apply(img, stages);
+            &preprocessed
         }
+    };
+
+    let (rendered_glyphs, cell) = (&atlas.glyphs, atlas.cell);
+    let (kx, ky) = (cell.width, cell.height);
+
+    let corrected;
+    let img = match options.aspect_correction {
+        false => img,
+        true => {
+            let display = options.cell_aspect.unwrap_or_else(|| cell.aspect());
+            corrected = correct_aspect(img, kx, ky, display);
+            &corrected
+        }
+    };
+
+    let (width, height) = img.dimensions();
+    let stride_x = options.stride_x.unwrap_or(kx).max(1) as usize;
+    let stride_y = options.stride_y.unwrap_or(ky).max(1) as usize;
+
+    let matcher = Matcher::new(rendered_glyphs, kx, ky, options);
+
+    let mut out: RgbaImage = ImageBuffer::new(width, height);
+
+    let rows: Vec<u32> = (0..height.saturating_sub(ky)).step_by(stride_y).collect();
+    let columns: Vec<u32> = (0..width.saturating_sub(kx)).step_by(stride_x).collect();
+    let total_rows = rows.len() as u32;
+
+    let bayer = options.dither.bayer_size().map(|n| (n, dither::bayer_matrix(n)));
+    let mut errors = ErrorGrid::new(columns.len(), rows.len());
+    let mut matches = Vec::with_capacity(rows.len());
+
+    for (row, &y) in rows.iter().enumerate() {
+        let results: Vec<(Option<usize>, u32)> = if options.dither.is_error_diffusion() {
+            // Each cell depends on the residual of the ones before it.
+            columns.iter().enumerate().map(|(col, &x)| {
+                if matcher.is_empty(img, x, y) {
+                    return (None, x);
+                }
+
+                let error = errors.get(col, row);
+                let mut tile = matcher.extract(img, x, y);
+                dither::offset_tile(&mut tile, error);
+                let index = matcher.match_tile(&tile, img, x, y);
+
+                // The residual is taken in sRGB whatever `colour_space` is,
+                // as it offsets the sRGB tile above.
+                let cell = dither::mean_colour(&PackedTile::from_view(img, x, y, kx, ky));
+                let glyph = matcher.glyph_mean(index);
+                // Clamp the target so colours the palette can't reach don't
+                // build up error without bound.
+                let mut residual = [0.0; kernel::CHANNELS];
+                for c in 0..kernel::CHANNELS {
+                    let target = (cell[c] + error[c]).clamp(0.0, 255.0);
+                    residual[c] = (target - glyph[c]) * options.dither_strength;
+                }
+                errors.diffuse(options.dither, col, row, residual);
+
+                (Some(index), x)
+            }).collect()
+        } else {
+            columns.par_iter().enumerate().map(|(col, &x)| {
+                if matcher.is_empty(img, x, y) {
+                    return (None, x);
+                }
+
+                let mut tile = matcher.extract(img, x, y);
+                if let Some((n, thresholds)) = &bayer {
+                    let t = thresholds[(row % n) * n + col % n] * options.dither_strength * 64.0;
+                    dither::offset_tile(&mut tile, [t; kernel::CHANNELS]);
+                }
+                (Some(matcher.match_tile(&tile, img, x, y)), x)
+            }).collect()
+        };
+
+        // Empty cells stay transparent.
+        for &(index, x) in &results {
+            if let Some(index) = index {
+                imageops::overlay(&mut out, &rendered_glyphs[index], x as i64, y as i64);
+            }
+        }
+        matches.push(results.into_iter().map(|(index, _)| index).collect());
+
+        progress(row as u32 + 1, total_rows);
+    }
 
-        let glyphId = GlyphId(i);
+    (out, matches)
+}
+
+
+/// Stretches `img` horizontally so `kx` x `ky` pixel cells, displayed as
+/// cells of aspect `display`, show the image at its original aspect.
+pub fn correct_aspect(img: &DynamicImage, kx: u32, ky: u32, display: f32) -> DynamicImage {
+    let factor = (kx as f32 / ky as f32) / display.max(f32::EPSILON);
+    let width = (img.width() as f32 * factor).round().max(1.0) as u32;
+    if width == img.width() {
+        return img.clone();
+    }
+
+    img.resize_exact(width, img.height(), imageops::FilterType::CatmullRom)
+}
 
-        let scaled_glyph = font.glyph(glyphId).scaled(scale);
-        let height = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).height().ceil() as u32 + 1;
-        let width = scaled_glyph.exact_bounding_box().unwrap_or(default_rect).width().ceil() as u32 + 1;
+/// The packed glyphs and scoring setup shared by every cell of a render.
+pub struct Matcher {
+    kx: u32,
+    ky: u32,
+    wx: u32,
+    wy: u32,
+    mx: i64,
+    my: i64,
+    mask: Option<Vec<f32>>,
+    glyphs: Vec<PackedTile>,
+    colour_space: ColourSpace,
+    /// `glyphs` converted to `colour_space`, empty for sRGB.
+    glyphs_converted: Vec<PackedTileF32>,
+    edge_weight: f32,
+    glyph_edges: Vec<EdgeDescriptor>,
+    glyph_means: Vec<[f32; kernel::CHANNELS]>,
+    transparency: bool,
+    alpha_threshold: f32,
+}
 
-        if height == 0 || width == 0 {
-            continue;
+impl Matcher {
+    /// `kx` x `ky` is the cell size all `rendered_glyphs` share.
+    pub fn new(rendered_glyphs: &[RgbaImage], kx: u32, ky: u32, options: &RenderOptions) -> Matcher {
+        // The comparison window is centred on the cell.
+        let window_scale = options.window_scale.max(1.0);
+        let (wx, wy) = ((kx as f32 * window_scale).round() as u32, (ky as f32 * window_scale).round() as u32);
+        let (mx, my) = (((wx - kx) / 2) as i64, ((wy - ky) / 2) as i64);
+        let mask = if (wx, wy) == (kx, ky) {
+            None
+        } else {
+            Some(window_mask(wx, wy, kx, ky, options.window_mask))
+        };
+
+        // Pack the glyphs once, tiles are packed once per cell. Glyph windows
+        // repeat their edge pixels (usually the background) as context.
+        let glyphs: Vec<PackedTile> = rendered_glyphs.iter()
+            .map(|glyph| match mask {
+                None => PackedTile::from_rgba(glyph),
+                Some(_) => PackedTile::from_view_clamped(glyph, -mx, -my, wx, wy),
+            })
+            .collect();
+
+        let colour_space = options.colour_space;
+        let glyphs_converted = match colour_space {
+            ColourSpace::Srgb => Vec::new(),
+            space => glyphs.par_iter().map(|glyph| glyph.to_colour_space(space)).collect(),
+        };
+
+        let edge_weight = options.edge_weight.clamp(0.0, 1.0);
+        let glyph_edges = if edge_weight > 0.0 {
+            rendered_glyphs.iter()
+                .map(|glyph| edges::edge_descriptor(&PackedTile::from_rgba(glyph)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let glyph_means = rendered_glyphs.iter()
+            .map(|glyph| dither::mean_colour(&PackedTile::from_rgba(glyph)))
+            .collect();
+
+        Matcher {
+            kx, ky, wx, wy, mx, my, mask, glyphs, colour_space, glyphs_converted, edge_weight, glyph_edges, glyph_means,
+            transparency: options.transparency,
+            alpha_threshold: options.alpha_threshold,
         }
+    }
 
+    /// Index of the best glyph for the cell at (`x`, `y`).
+    pub fn match_at(&self, img: &DynamicImage, x: u32, y: u32) -> usize {
+        self.match_tile(&self.extract(img, x, y), img, x, y)
+    }
 
-        let positioned_glyph = scaled_glyph.positioned(point);
+    /// The comparison window for the cell at (`x`, `y`).
+    pub fn extract(&self, img: &DynamicImage, x: u32, y: u32) -> PackedTile {
+        match self.mask {
+            None => PackedTile::from_view(img, x, y, self.kx, self.ky),
+            Some(_) => PackedTile::from_view_clamped(img, x as i64 - self.mx, y as i64 - self.my, self.wx, self.wy),
+        }
+    }
 
-        println!("width: {}, height: {}", width, height);
-        glyph_width = std::cmp::max(glyph_width, width);
-        glyph_height = std::cmp::max(glyph_height, height);
+    /// Whether the cell at (`x`, `y`) is transparent enough to leave empty.
+    pub fn is_empty(&self, img: &DynamicImage, x: u32, y: u32) -> bool {
+        if !self.transparency {
+            return false;
+        }
 
+        let alpha: u64 = img.view(x, y, self.kx, self.ky).pixels().map(|(_, _, p)| p[3] as u64).sum();
+        (alpha as f32 / (self.kx * self.ky) as f32 / 255.0) < self.alpha_threshold
+    }
 
-        for background_colour in &glyph_background {
+    /// Per-sample weights for the window at (`x`, `y`): the window mask
+    /// scaled by each pixel's alpha. `None` when every weight would be 1.
+    /// A fully transparent window would weigh every glyph the same, so its
+    /// colours are compared as they are.
+    fn weights(&self, img: &DynamicImage, x: u32, y: u32) -> Option<Cow<'_, [f32]>> {
+        if !self.transparency || !img.color().has_alpha() {
+            return self.mask.as_deref().map(Cow::Borrowed);
+        }
 
-            let mut background;
-            if glyph_background_size == "glyph-min" {
-                background = DynamicImage::new_rgba8(width, height).to_rgba8();
-            } else if glyph_background_size == "glyph-max" {
-                background = DynamicImage::new_rgba8(max_width, max_height).to_rgba8();
-            } else {
-                background = DynamicImage::new_rgba8(width, height).to_rgba8();
+        let (ox, oy) = (x as i64 - self.mx, y as i64 - self.my);
+        let (iw, ih) = img.dimensions();
+        let mut weights = Vec::with_capacity((self.wx * self.wy) as usize * kernel::CHANNELS);
+        let (mut opaque, mut visible) = (true, false);
+        for py in oy..oy + self.wy as i64 {
+            for px in ox..ox + self.wx as i64 {
+                let a = img.get_pixel(px.clamp(0, iw as i64 - 1) as u32, py.clamp(0, ih as i64 - 1) as u32)[3];
+                opaque &= a == 255;
+                visible |= a > 0;
+                weights.extend_from_slice(&[a as f32 / 255.0; kernel::CHANNELS]);
             }
+        }
+
+        if opaque || !visible {
+            return self.mask.as_deref().map(Cow::Borrowed);
+        }
+        if let Some(mask) = &self.mask {
+            for (w, m) in weights.iter_mut().zip(mask) {
+                *w *= m;
+            }
+        }
+        Some(Cow::Owned(weights))
+    }
+
+    /// Average sRGB colour of a glyph render.
+    pub fn glyph_mean(&self, index: usize) -> [f32; kernel::CHANNELS] {
+        self.glyph_means[index]
+    }
 
-            paint_background(&mut background, background_colour);
-            for glyph_colour in &glyph_colours {
-                //if let Some(bb) = glyph.pixel_bounding_box() {
-                let mut glyph = background.clone();
-                paint_character(&mut glyph, glyph_colour, &positioned_glyph);
-                // Could add rotation as well
-                glyph.save(format!("./images/glyphs/{}.png", counter)).expect("TODO: panic message");
-                counter += 1;
+    /// Index of the best glyph for `tile`, a (possibly adjusted) window
+    /// returned by [`Matcher::extract`] for the cell at (`x`, `y`).
+    pub fn match_tile(&self, tile: &PackedTile, img: &DynamicImage, x: u32, y: u32) -> usize {
+        let weights = self.weights(img, x, y);
+        if self.edge_weight <= 0.0 && self.colour_space == ColourSpace::Srgb {
+            return match &weights {
+                None => match_character(tile, &self.glyphs),
+                Some(weights) => match_character_weighted(tile, &self.glyphs, weights),
+            };
+        }
 
-                glyph_width = glyph_width.max(glyph.width());
-                glyph_height = glyph_height.max(glyph.height());
+        let converted = match self.colour_space {
+            ColourSpace::Srgb => None,
+            space => Some(tile.to_colour_space(space)),
+        };
 
-                rendered_glyphs.push(glyph);
+        // Blend the intensity distance (normalised to 0..1) with the edge
+        // distance of the cell itself.
+        let tile_edges = match (self.edge_weight > 0.0, &self.mask) {
+            (false, _) => None,
+            (true, None) => Some(edges::edge_descriptor(tile)),
+            (true, Some(_)) => Some(edges::edge_descriptor(&PackedTile::from_view(img, x, y, self.kx, self.ky))),
+        };
+        let weight_total = weights.as_ref().map(|weights| weights.iter().sum::<f32>().max(f32::EPSILON) * 255.0);
+
+        let mut min_idx = 0;
+        let mut min_value = f32::MAX;
+        for (idx, character) in self.glyphs.iter().enumerate() {
+            let intensity = match (&converted, &weights, weight_total) {
+                (None, Some(weights), Some(total)) => kernel::tile_distance_weighted(tile, character, weights) / total,
+                (Some(tile), Some(weights), Some(total)) => {
+                    kernel::tile_distance_weighted_f32(tile, &self.glyphs_converted[idx], weights) / total
+                }
+                (None, _, _) => {
+                    let (distance, pixels) = kernel::tile_distance(tile, character);
+                    distance as f32 / (pixels as f32 * kernel::CHANNELS as f32 * 255.0)
+                }
+                (Some(tile), _, _) => {
+                    let (distance, pixels) = kernel::tile_distance_f32(tile, &self.glyphs_converted[idx]);
+                    distance / (pixels as f32 * kernel::CHANNELS as f32 * 255.0)
+                }
+            };
+            let value = match &tile_edges {
+                None => intensity,
+                Some(tile_edges) => {
+                    let edge = edges::edge_distance(tile_edges, &self.glyph_edges[idx]);
+                    (1.0 - self.edge_weight) * intensity + self.edge_weight * edge
+                }
+            };
 
+            if value < min_value {
+                min_value = value;
+                min_idx = idx;
             }
         }
 
-        glyphs.push(positioned_glyph);
+        min_idx
+    }
+}
+
+
+/// Glyph origins on an `n` x `n` grid covering one pixel, starting at 0.
+pub fn subpixel_points(n: u32) -> Vec<Point<f32>> {
+    let n = n.max(1);
+    let mut points = Vec::with_capacity((n * n) as usize);
+    for j in 0..n {
+        for i in 0..n {
+            points.push(Point {
+                x: i as f32 / n as f32,
+                y: j as f32 / n as f32
+            });
+        }
+    }
+
+    points
+}
 
+/// Per-sample weights for a `wx` x `wy` window around a centred `kx` x `ky`
+/// cell.
+pub fn window_mask(wx: u32, wy: u32, kx: u32, ky: u32, mask: WindowMask) -> Vec<f32> {
+    let (mx, my) = ((wx - kx) / 2, (wy - ky) / 2);
+    let sigma = (std::cmp::max(mx, my) as f32 / 2.0).max(0.5);
+
+    let mut weights = Vec::with_capacity((wx * wy) as usize * kernel::CHANNELS);
+    for y in 0..wy {
+        for x in 0..wx {
+            // Distance from the pixel to the cell, 0 inside it.
+            let dx = mx.saturating_sub(x).max(x.saturating_sub(mx + kx - 1)) as f32;
+            let dy = my.saturating_sub(y).max(y.saturating_sub(my + ky - 1)) as f32;
+
+            let w = match mask {
+                WindowMask::Flat => 1.0,
+                WindowMask::Gaussian => (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp(),
+            };
+            weights.extend_from_slice(&[w; kernel::CHANNELS]);
+        }
     }
 
+    weights
+}
 
-    println!("glyph_width: {}, glyph_height: {}", glyph_width, glyph_height);
-    //let mut rendered_glyphs : Vec<RgbaImage> = Vec::new();
+pub fn filter_diff(kernel: &PackedTile, character: &PackedTile) -> f64 {
+    let (distance, pixels) = kernel::tile_distance(kernel, character);
 
-    println!("Rendering glyphs into bitmaps.");
-    for glyph in glyphs {
-        let mut image = DynamicImage::new_rgba8(glyph_width, glyph_height).to_rgba8();
+    // Each channel scores 255 - |a - b|.
+    let s = (pixels as u64 * kernel::CHANNELS as u64 * 255) as f64 - distance as f64;
 
-        if let Some(bb) = glyph.pixel_bounding_box() {
-            let mut image = DynamicImage::new_rgba8(glyph_width, glyph_height).to_rgba8();
-            let mut image = DynamicImage::new_rgba8(glyph_width, glyph_height).to_rgba8();
+    s / (pixels as f64)
+}
 
-            if let Some(bb) = glyph.pixel_bounding_box() {
-                let o = |x, y, v| {
-                    image.put_pixel(
-                        // Offset the position by the glyph bounding box
-                        x as u32,
-                        y as u32,
+pub fn match_character(kernel: &PackedTile, characters: &[PackedTile]) -> usize {
 
-                        // Turn the coverage into an alpha value
-                        Rgba([255, 255, 255, (v * 255.0) as u8]),
-                    )
-                };
+    let mut max_idx = 0;
+    let mut max_value : f64 = -1.0;
+    for (idx, character) in characters.iter().enumerate() {
+        let value = filter_diff(kernel, character);
 
-                glyph.draw(o);
-                //let path = format!("./export/{}.png", counter);
-                //image.save(path).expect("TODO: panic message");
+        if value > max_value {
+            max_value = value;
+            max_idx = idx;
+        }
 
-                rendered_glyphs.push(image);
-            }
+    }
+
+    max_idx
+}
+
+/// [`match_character`] for windows scored through a weighting mask. The
+/// windows and mask must all have the same size.
+pub fn match_character_weighted(kernel: &PackedTile, characters: &[PackedTile], mask: &[f32]) -> usize {
+    let mut min_idx = 0;
+    let mut min_value = f32::MAX;
+    for (idx, character) in characters.iter().enumerate() {
+        let value = kernel::tile_distance_weighted(kernel, character, mask);
+
+        if value < min_value {
+            min_value = value;
+            min_idx = idx;
         }
     }
-    let (width, height) = img.dimensions();
-    let (kx, ky) = (glyph_width, glyph_height);
-    let (stride_x, stride_y) = (glyph_width as usize, glyph_height as usize);
 
-    let mut img_gray = img.grayscale();
-    let mut img_cp = img.clone();
-    let mut out: RgbaImage = ImageBuffer::new(width, height);
+    min_idx
+}
 
-    // 1. Filter Size
+pub fn paint_background(img: &mut image::RgbaImage, colour: &image::Rgba<u8>) {
+    for x in 0..img.width() {
+        for y in 0..img.height() {
+           img.put_pixel(x, y, *colour) ;
+        }
+    }
+}
 
+/// How glyph coverage is turned into alpha and composited over the cell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coverage {
+    pub gamma: f32,
+    pub antialias: bool,
+}
 
-    /*
-    counter = 0;
-    let max_counter = ((width - kx)/stride_x as u32) * ((height - ky)/stride_y as u32);
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage { gamma: 1.0, antialias: true }
+    }
+}
+
+impl Coverage {
+    pub fn from_options(options: &RenderOptions) -> Coverage {
+        Coverage { gamma: options.glyph_gamma.max(f32::EPSILON), antialias: options.glyph_antialias }
+    }
 
-    for x in (0..width - kx).step_by(stride_x) {
-        for y in (0..height - ky).step_by(stride_y) {
-            println!("{} of {}", counter, max_counter);
-            counter += 1;
-            let subimg = imageops::crop(&mut img, x, y, kx, ky);
-            let index = match_character(&subimg, &rendered_glyphs);
-            let character = &rendered_glyphs[index];
-            imageops::overlay(&mut out, character, x as i64, y as i64);
+    /// Alpha for a rasteriser coverage value.
+    pub fn alpha(self, v: f32) -> f32 {
+        match self.antialias {
+            true => v,
+            false if v >= 0.5 => 1.0,
+            false => 0.0,
         }
     }
-    */
 
-    let total_kernel_ops = ((width - kx) / stride_x as u32) * ((height - ky) / stride_y as u32);
-    let mut data = vec![];
-    for x in (0..width - kx).step_by(stride_x) {
-        for y in (0..height - ky).step_by(stride_y) {
-            let subimg = img.view(x, y, kx, ky);
-            let item = (subimg, x, y);
-            data.push(item);
+    /// Composites `colour` over `pixel` at `alpha`, in the space `gamma`
+    /// decodes the channels to.
+    pub fn blend(self, pixel: &mut Rgba<u8>, colour: Rgba<u8>, alpha: f32) {
+        if self.gamma == 1.0 {
+            pixel.blend(&Rgba([colour[0], colour[1], colour[2], (alpha * colour[3] as f32) as u8]));
+            return;
+        }
+
+        let src_alpha = alpha * colour[3] as f32 / 255.0;
+        let dst_alpha = pixel[3] as f32 / 255.0;
+        let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+        if out_alpha <= 0.0 {
+            return;
+        }
+
+        let decode = |v: u8| (v as f32 / 255.0).powf(self.gamma);
+        let encode = |v: f32| (v.clamp(0.0, 1.0).powf(1.0 / self.gamma) * 255.0).round() as u8;
+        for c in 0..3 {
+            let mixed = decode(colour[c]) * src_alpha + decode(pixel[c]) * dst_alpha * (1.0 - src_alpha);
+            pixel[c] = encode(mixed / out_alpha);
         }
+        pixel[3] = (out_alpha * 255.0).round() as u8;
     }
+}
+
+pub fn paint_character(img: &mut image::RgbaImage, colour: &image::Rgba<u8>, glyph: &PositionedGlyph, coverage: Coverage) {
+    paint_character_at(img, colour, glyph, 0, 0, coverage);
+}
+
+/// Paints `glyph` shifted by (`dx`, `dy`) pixels from its bounding box origin.
+pub fn paint_character_at(
+    img: &mut image::RgbaImage,
+    colour: &image::Rgba<u8>,
+    glyph: &(impl Rasterise + ?Sized),
+    dx: i32,
+    dy: i32,
+    coverage: Coverage,
+) {
+    let mut o = |x: u32, y: u32, v: f32| {
+        // Offset the position by the glyph bounding box
+        let (x, y) = (x as i32 + dx, y as i32 + dy);
+        if x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+            coverage.blend(img.get_pixel_mut(x as u32, y as u32), *colour, coverage.alpha(v));
+        }
+    };
 
-    let results = Arc::new(Mutex::new(Vec::with_capacity(data.len())));
-    let start_time = Instant::now(); // Record the start time before the loop starts
+    glyph.draw(&mut o);
+}
 
-    data.par_iter().for_each(|item| {
-        let subimg = &item.0;
-        let x = &item.1;
-        let y = &item.2;
 
-        let index = match_character(subimg, &rendered_glyphs);
-        let result = (index, x, y);
-        let mut results_guard = results.lock().unwrap();
-        results_guard.push(result);
-        print_eta(results_guard.len() as u32, total_kernel_ops, start_time);
+/// Coverage of `glyph` in a cell as [`paint_character_at`] draws it, with
+/// the second channel `1` where the rasteriser touched a pixel.
+pub fn coverage_mask(
+    glyph: &dyn Rasterise,
+    dx: i32,
+    dy: i32,
+    cell: CellMetrics,
+    coverage: Coverage,
+) -> ImageBuffer<LumaA<f32>, Vec<f32>> {
+    let mut mask = ImageBuffer::new(cell.width, cell.height);
+    glyph.draw(&mut |x, y, v| {
+        let (x, y) = (x as i32 + dx, y as i32 + dy);
+        if x >= 0 && y >= 0 && (x as u32) < mask.width() && (y as u32) < mask.height() {
+            mask.put_pixel(x as u32, y as u32, LumaA([coverage.alpha(v), 1.0]));
+        }
     });
+    mask
+}
+
+pub fn print_eta(completed: u32, total: u32, start_time: Instant){
+    let percentage = (completed as f64 / total as f64) * 100.0;
+    let elapsed_time = start_time.elapsed().as_secs_f64(); // Calculate the elapsed time
+    let eta = if completed > 0 {
+        elapsed_time * (total as f64/ completed as f64 - 1.0)
+    } else {
+        0.0
+    };
 
-    let results_guard = results.lock().unwrap();
-    for result in results_guard.iter() {
-        let index = result.0;
-        let x = result.1;
-        let y = result.2;
+    let eta_mins = (eta / 60.0).floor();
+    let eta_secs = (eta % 60.0).floor();
+
+    println!("{:.2}%, ETA: {:.0}m {:.0}s", percentage, eta_mins, eta_secs);
+}
 
-        let character = &rendered_glyphs[index];
-        imageops::overlay(&mut out, character, *x as i64, *y as i64);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fonts() -> FontStack {
+        FontStack::new(vec![include_bytes!("../fonts/Arial-Monospaced.ttf").to_vec()]).unwrap()
+    }
+
+    #[test]
+    fn no_candidates_is_an_error() {
+        let options = RenderOptions { charset: Some("\u{2800}".to_string()), ..RenderOptions::default() };
+        let (background, foreground) = default_colours();
+        let result = render_glyphs(&fonts(), &background, &foreground, &options);
+        assert!(matches!(result, Err(RenderError::NoCharacters)));
+    }
+
+    #[test]
+    fn empty_glyph_range_is_an_error() {
+        let fonts = fonts();
+        let font = fonts.primary();
+        let last = font.glyph_count() as u16 - 1;
+        let range = |glyph_start, glyph_end| glyph_range(font, &RenderOptions { glyph_start, glyph_end, ..RenderOptions::default() });
+
+        assert_eq!(range(36, None).unwrap(), 36..=last);
+        assert_eq!(range(36, Some(u16::MAX)).unwrap(), 36..=last);
+        assert_eq!(range(10, Some(10)).unwrap(), 10..=10);
+        assert!(matches!(range(500, Some(10)), Err(RenderError::EmptyGlyphRange { start: 500, end: 10, .. })));
+        assert!(matches!(range(last + 1, None), Err(RenderError::EmptyGlyphRange { .. })));
     }
 
-    out.save("./images/output/".to_owned() + image_name).expect("TODO: panic message");
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+    /// A 4 x 4 image, `left` in the first two columns and `right` in the others.
+    fn halves(left: Rgba<u8>, right: Rgba<u8>) -> RgbaImage {
+        RgbaImage::from_fn(4, 4, |x, _| if x < 2 { left } else { right })
+    }
+
+    /// Left white and right black, then light grey and white.
+    fn matcher(options: &RenderOptions) -> Matcher {
+        Matcher::new(&[halves(WHITE, BLACK), halves(Rgba([230, 230, 230, 255]), WHITE)], 4, 4, options)
+    }
+
+    #[test]
+    fn empty_cells() {
+        let options = RenderOptions { transparency: true, alpha_threshold: 0.5, ..RenderOptions::default() };
+        let cell = |alpha| DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, alpha])));
+        assert!(matcher(&options).is_empty(&cell(0), 0, 0));
+        assert!(matcher(&options).is_empty(&cell(120), 0, 0));
+        assert!(!matcher(&options).is_empty(&cell(135), 0, 0));
+
+        // Half transparent, half opaque: a mean alpha of exactly 0.5.
+        let half = DynamicImage::ImageRgba8(halves(WHITE, Rgba([255, 255, 255, 0])));
+        assert!(!matcher(&options).is_empty(&half, 0, 0));
+        assert!(matcher(&RenderOptions { alpha_threshold: 0.6, ..options.clone() }).is_empty(&half, 0, 0));
+
+        let opaque = RenderOptions { transparency: false, ..options };
+        assert!(!matcher(&opaque).is_empty(&cell(0), 0, 0));
+    }
+
+    #[test]
+    fn alpha_weights() {
+        let options = RenderOptions { transparency: true, alpha_threshold: 0.0, ..RenderOptions::default() };
+        let matcher = matcher(&options);
+
+        let opaque = DynamicImage::ImageRgba8(halves(WHITE, WHITE));
+        assert!(matcher.weights(&opaque, 0, 0).is_none());
+        let rgb = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([255, 255, 255])));
+        assert!(matcher.weights(&rgb, 0, 0).is_none());
+
+        let faded = DynamicImage::ImageRgba8(halves(WHITE, Rgba([255, 255, 255, 51])));
+        let weights = matcher.weights(&faded, 0, 0).unwrap();
+        assert_eq!(weights.len(), 16 * kernel::CHANNELS);
+        assert_eq!(weights[0], 1.0);
+        assert!((weights[2 * kernel::CHANNELS] - 0.2).abs() < 1e-6);
+
+        // Zero weights fall back to comparing colours.
+        let clear = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 0])));
+        assert!(matcher.weights(&clear, 0, 0).is_none());
+        assert_eq!(matcher.match_at(&clear, 0, 0), 1);
+    }
+
+    #[test]
+    fn weighting_changes_the_match() {
+        // The right half is white but transparent.
+        let img = DynamicImage::ImageRgba8(halves(WHITE, Rgba([255, 255, 255, 0])));
+        let weighted = RenderOptions { transparency: true, alpha_threshold: 0.0, ..RenderOptions::default() };
+        assert_eq!(matcher(&weighted).match_at(&img, 0, 0), 0);
+        assert_eq!(matcher(&RenderOptions { transparency: false, ..weighted.clone() }).match_at(&img, 0, 0), 1);
+        // The same holds when scoring in another colour space.
+        let lab = RenderOptions { colour_space: ColourSpace::Lab, ..weighted };
+        assert_eq!(matcher(&lab).match_at(&img, 0, 0), 0);
+    }
+
+    #[test]
+    fn aspect_correction_resizes_the_width() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 50, WHITE));
+        // 10 x 20 cells shown at 1:2 need no correction.
+        assert_eq!(correct_aspect(&img, 10, 20, 0.5).dimensions(), (100, 50));
+        // Shown square, each cell covers twice as much of the image's width.
+        assert_eq!(correct_aspect(&img, 10, 20, 1.0).dimensions(), (50, 50));
+        assert_eq!(correct_aspect(&img, 10, 20, 0.25).dimensions(), (200, 50));
+        assert_eq!(correct_aspect(&img, 10, 20, 1e6).dimensions(), (1, 50));
+    }
+
+    #[test]
+    fn aspect_correction_changes_the_cell_grid() {
+        let options = RenderOptions { charset: Some("ab".to_string()), ..RenderOptions::default() };
+        let atlas = GlyphAtlas::build(String::new(), &fonts(), &[BLACK], &[WHITE], &options).unwrap();
+        let (kx, ky) = (atlas.cell.width, atlas.cell.height);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(kx * 21, ky * 6, |x, y| if (x / 3 + y / 5) % 2 == 0 { WHITE } else { BLACK }));
+
+        let grid = |aspect_correction, cell_aspect| {
+            let options = RenderOptions { aspect_correction, cell_aspect, ..options.clone() };
+            let (out, matches) = render_with_atlas(&img, &atlas, &options, &mut |_, _| {});
+            (out.dimensions(), matches.len(), matches[0].len())
+        };
+
+        let natural = kx as f32 / ky as f32;
+        assert_eq!(grid(false, None), ((kx * 21, ky * 6), 5, 20));
+        assert_eq!(grid(true, Some(natural)), ((kx * 21, ky * 6), 5, 20));
+        // Cells shown half as wide: twice the columns, the same rows.
+        assert_eq!(grid(true, Some(natural / 2.0)), ((kx * 42, ky * 6), 5, 41));
+        assert_eq!(grid(true, Some(natural * 3.0)), ((kx * 7, ky * 6), 5, 6));
+    }
+
+    #[test]
+    fn window_masks() {
+        // An 8 x 10 window around a 4 x 6 cell, 2 pixels of context each side.
+        let (wx, wy, kx, ky) = (8, 10, 4, 6);
+        let weight = |mask: &[f32], x: u32, y: u32| {
+            let i = (y * wx + x) as usize * kernel::CHANNELS;
+            let pixel = &mask[i..i + kernel::CHANNELS];
+            assert!(pixel.iter().all(|&w| w == pixel[0]));
+            pixel[0]
+        };
+
+        let flat = window_mask(wx, wy, kx, ky, WindowMask::Flat);
+        assert_eq!(flat.len(), (wx * wy) as usize * kernel::CHANNELS);
+        assert!(flat.iter().all(|&w| w == 1.0));
+
+        let gaussian = window_mask(wx, wy, kx, ky, WindowMask::Gaussian);
+        assert_eq!(gaussian.len(), flat.len());
+        for y in 0..wy {
+            for x in 0..wx {
+                let w = weight(&gaussian, x, y);
+                let inside = (2..6).contains(&x) && (2..8).contains(&y);
+                assert_eq!(w == 1.0, inside, "{} {}", x, y);
+                // Symmetric about the cell.
+                assert_eq!(w, weight(&gaussian, wx - 1 - x, y));
+                assert_eq!(w, weight(&gaussian, x, wy - 1 - y));
+            }
+        }
+        // Sigma is half the margin, and weights fall off with distance.
+        assert!((weight(&gaussian, 1, 4) - (-0.5f32).exp()).abs() < 1e-6);
+        assert!((weight(&gaussian, 0, 4) - (-2.0f32).exp()).abs() < 1e-6);
+        assert!((weight(&gaussian, 1, 1) - (-1.0f32).exp()).abs() < 1e-6);
+        assert!(weight(&gaussian, 0, 0) < weight(&gaussian, 1, 1));
+
+        // Without context every weight is 1.
+        assert!(window_mask(kx, ky, kx, ky, WindowMask::Gaussian).iter().all(|&w| w == 1.0));
+    }
 }
@@ -0,0 +1,271 @@
+use image::{imageops, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::dedup::GlyphMerge;
+use crate::fonts::FontStack;
+use crate::style::GlyphStyle;
+use crate::variant::GlyphVariant;
+use crate::FontImageTransformer::{render_glyphs, CellMetrics, GlyphRender, RenderError, RenderOptions};
+
+// Glyph atlas.
+//
+// Rasterising every glyph x background x foreground combination is the slow
+// part of setting up a render, and it only depends on the font, the palettes
+// and the options that change how glyphs are drawn. The renders are kept in a
+// `GlyphAtlas`, stored as one PNG with the cells packed in a grid plus a JSON
+// index, and keyed by a hash of everything they depend on. The CLI keeps
+// atlases in a cache directory; the wasm side keeps the last one in memory
+// and can hand it to JS to persist (`atlas_png`, `atlas_index`,
+// `set_atlas`).
+
+const VERSION: u32 = 6;
+
+/// Everything besides the font files that the glyph renders depend on.
+#[derive(Serialize)]
+struct AtlasParams {
+    version: u32,
+    scale_x: f32,
+    scale_y: f32,
+    glyph_start: u16,
+    glyph_end: Option<u16>,
+    charset: Option<String>,
+    subpixel_offsets: u32,
+    glyph_gamma: f32,
+    glyph_antialias: bool,
+    proportional: String,
+    prune_distance: f32,
+    glyph_variants: Vec<GlyphVariant>,
+    glyph_styles: Vec<GlyphStyle>,
+    embolden: f32,
+    oblique: f32,
+    /// The style each font of the stack supplies.
+    font_styles: Vec<GlyphStyle>,
+    glyph_background: Vec<[u8; 4]>,
+    glyph_colours: Vec<[u8; 4]>,
+}
+
+/// The JSON stored next to the atlas image.
+#[derive(Serialize, Deserialize)]
+struct AtlasIndex {
+    version: u32,
+    key: String,
+    cell: CellMetrics,
+    columns: u32,
+    renders: Vec<GlyphRender>,
+    merged: Vec<GlyphMerge>,
+}
+
+/// Where [`GlyphAtlas::load_or_build`] got the atlas from.
+#[derive(Debug)]
+pub enum AtlasSource {
+    /// Read from the cache.
+    Cached,
+    /// Rasterised and saved to the cache.
+    Built,
+    /// Rasterised, but the cache could not be written.
+    Unsaved(std::io::Error),
+}
+
+pub struct GlyphAtlas {
+    pub key: String,
+    pub cell: CellMetrics,
+    pub glyphs: Vec<RgbaImage>,
+    /// What each of `glyphs` shows.
+    pub renders: Vec<GlyphRender>,
+    /// Glyphs left out as duplicates of others.
+    pub merged: Vec<GlyphMerge>,
+}
+
+impl GlyphAtlas {
+    /// Content hash identifying the atlas for these inputs.
+    pub fn key(fonts: &FontStack, glyph_background: &[Rgba<u8>], glyph_colours: &[Rgba<u8>], options: &RenderOptions) -> String {
+        let params = AtlasParams {
+            version: VERSION,
+            scale_x: options.scale_x,
+            scale_y: options.scale_y,
+            glyph_start: options.glyph_start,
+            glyph_end: options.glyph_end,
+            charset: options.charset.clone(),
+            subpixel_offsets: options.subpixel_offsets,
+            glyph_gamma: options.glyph_gamma,
+            glyph_antialias: options.glyph_antialias,
+            proportional: format!("{:?}", options.proportional),
+            prune_distance: options.prune_distance,
+            glyph_variants: options.glyph_variants.clone(),
+            glyph_styles: options.glyph_styles.clone(),
+            embolden: options.embolden,
+            oblique: options.oblique,
+            font_styles: fonts.styles().to_vec(),
+            glyph_background: glyph_background.iter().map(|c| c.0).collect(),
+            glyph_colours: glyph_colours.iter().map(|c| c.0).collect(),
+        };
+        let params = serde_json::to_vec(&params).expect("atlas parameters serialise");
+
+        let hash = fonts.data().iter().fold(FNV_OFFSET, |hash, data| {
+            fnv1a(fnv1a(hash, &(data.len() as u64).to_le_bytes()), data)
+        });
+        format!("{:016x}", fnv1a(hash, &params))
+    }
+
+    /// Rasterises the glyphs.
+    pub fn build(
+        key: String,
+        fonts: &FontStack,
+        glyph_background: &[Rgba<u8>],
+        glyph_colours: &[Rgba<u8>],
+        options: &RenderOptions,
+    ) -> Result<GlyphAtlas, RenderError> {
+        let (glyphs, renders, cell, merged) = render_glyphs(fonts, glyph_background, glyph_colours, options)?;
+        Ok(GlyphAtlas { key, cell, glyphs, renders, merged })
+    }
+
+    /// The atlas for these inputs from `dir`, rasterising and saving it there
+    /// first if it isn't cached yet, and where it came from. A cache that
+    /// can't be written isn't an error, the atlas is returned as
+    /// [`AtlasSource::Unsaved`].
+    pub fn load_or_build(
+        dir: &Path,
+        fonts: &FontStack,
+        glyph_background: &[Rgba<u8>],
+        glyph_colours: &[Rgba<u8>],
+        options: &RenderOptions,
+    ) -> Result<(GlyphAtlas, AtlasSource), RenderError> {
+        let key = GlyphAtlas::key(fonts, glyph_background, glyph_colours, options);
+        if let Some(atlas) = GlyphAtlas::load(dir, &key) {
+            return Ok((atlas, AtlasSource::Cached));
+        }
+
+        let atlas = GlyphAtlas::build(key, fonts, glyph_background, glyph_colours, options)?;
+        let source = match atlas.save(dir) {
+            Ok(()) => AtlasSource::Built,
+            Err(e) => AtlasSource::Unsaved(e),
+        };
+        Ok((atlas, source))
+    }
+
+    /// Reads `<dir>/<key>.png` and `<dir>/<key>.json`.
+    pub fn load(dir: &Path, key: &str) -> Option<GlyphAtlas> {
+        let index = std::fs::read_to_string(dir.join(format!("{}.json", key))).ok()?;
+        let image = image::open(dir.join(format!("{}.png", key))).ok()?.to_rgba8();
+        GlyphAtlas::unpack(&image, &index).filter(|atlas| atlas.key == key)
+    }
+
+    pub fn save(&self, dir: &Path) -> std::io::Result<()> {
+        let (image, index) = self.pack();
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join(format!("{}.json", self.key)), index)?;
+        image.save(dir.join(format!("{}.png", self.key))).map_err(std::io::Error::other)
+    }
+
+    /// The cells packed into a roughly square grid, and the JSON index.
+    pub fn pack(&self) -> (RgbaImage, String) {
+        let count = self.glyphs.len();
+        let columns = ((count as f64).sqrt().ceil() as u32).max(1);
+        let rows = (count as u32).div_ceil(columns).max(1);
+        let (cw, ch) = (self.cell.width, self.cell.height);
+
+        let mut image = RgbaImage::new(columns * cw, rows * ch);
+        for (i, glyph) in self.glyphs.iter().enumerate() {
+            let (col, row) = (i as u32 % columns, i as u32 / columns);
+            imageops::replace(&mut image, glyph, (col * cw) as i64, (row * ch) as i64);
+        }
+
+        let index = AtlasIndex {
+            version: VERSION,
+            key: self.key.clone(),
+            cell: self.cell,
+            columns,
+            renders: self.renders.clone(),
+            merged: self.merged.clone(),
+        };
+        (image, serde_json::to_string(&index).expect("atlas index serialises"))
+    }
+
+    /// Inverse of [`GlyphAtlas::pack`]. `None` if the index doesn't match
+    /// the image or was written by another version.
+    pub fn unpack(image: &RgbaImage, index: &str) -> Option<GlyphAtlas> {
+        let index: AtlasIndex = serde_json::from_str(index).ok()?;
+        let (cw, ch) = (index.cell.width, index.cell.height);
+        if index.version != VERSION || index.columns == 0 {
+            return None;
+        }
+        let count = index.renders.len() as u32;
+        let rows = count.div_ceil(index.columns);
+        if image.width() < index.columns * cw || image.height() < rows * ch {
+            return None;
+        }
+
+        let glyphs = (0..count)
+            .map(|i| {
+                let (col, row) = (i % index.columns, i / index.columns);
+                imageops::crop_imm(image, col * cw, row * ch, cw, ch).to_image()
+            })
+            .collect();
+
+        Some(GlyphAtlas { key: index.key, cell: index.cell, glyphs, renders: index.renders, merged: index.merged })
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// FNV-1a: stable across builds and platforms, unlike std's hasher.
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atlas() -> GlyphAtlas {
+        let fonts = FontStack::new(vec![include_bytes!("../fonts/Arial-Monospaced.ttf").to_vec()]).unwrap();
+        let colours = [Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255])];
+        let options = RenderOptions { charset: Some("a/|".to_string()), ..RenderOptions::default() };
+        let key = GlyphAtlas::key(&fonts, &colours, &colours, &options);
+        GlyphAtlas::build(key, &fonts, &colours, &colours, &options).unwrap()
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let atlas = atlas();
+        let (image, index) = atlas.pack();
+        let unpacked = GlyphAtlas::unpack(&image, &index).unwrap();
+
+        assert_eq!(unpacked.key, atlas.key);
+        assert_eq!((unpacked.cell.width, unpacked.cell.height), (atlas.cell.width, atlas.cell.height));
+        assert_eq!(unpacked.glyphs, atlas.glyphs);
+        assert_eq!(unpacked.renders.len(), atlas.renders.len());
+        assert!(unpacked.renders.iter().zip(&atlas.renders).all(|(a, b)| a.key() == b.key()));
+    }
+
+    #[test]
+    fn unpack_rejects_mismatches() {
+        let atlas = atlas();
+        let (image, index) = atlas.pack();
+
+        let old = index.replace(&format!("\"version\":{}", VERSION), &format!("\"version\":{}", VERSION - 1));
+        assert_ne!(old, index);
+        assert!(GlyphAtlas::unpack(&image, &old).is_none());
+        assert!(GlyphAtlas::unpack(&image, &index[..index.len() / 2]).is_none());
+        let cropped = imageops::crop_imm(&image, 0, 0, image.width(), image.height() - 1).to_image();
+        assert!(GlyphAtlas::unpack(&cropped, &index).is_none());
+
+        let dir = std::env::temp_dir().join(format!("glyph-atlas-test-{}", std::process::id()));
+        atlas.save(&dir).unwrap();
+        assert!(GlyphAtlas::load(&dir, &atlas.key).is_some());
+        // Another key's files, and a truncated image, are not used.
+        let other = "0123456789abcdef";
+        std::fs::copy(dir.join(format!("{}.json", atlas.key)), dir.join(format!("{}.json", other))).unwrap();
+        std::fs::copy(dir.join(format!("{}.png", atlas.key)), dir.join(format!("{}.png", other))).unwrap();
+        assert!(GlyphAtlas::load(&dir, other).is_none());
+        let png = std::fs::read(dir.join(format!("{}.png", atlas.key))).unwrap();
+        std::fs::write(dir.join(format!("{}.png", atlas.key)), &png[..png.len() / 2]).unwrap();
+        assert!(GlyphAtlas::load(&dir, &atlas.key).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
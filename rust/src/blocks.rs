@@ -0,0 +1,298 @@
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::FontImageTransformer::{init_thread_pool, RenderOptions};
+use crate::braille::{braille_cells, braille_character, paint_braille};
+use crate::preprocess;
+
+// Block "pixel" rendering.
+//
+// Instead of matching rasterised glyphs, every cell is split into 2 (half
+// blocks), 4 (quadrants) or 6 (sextants) sub-pixels and drawn with the block
+// character that best splits them into two groups. The foreground and
+// background are the mean colours of the two groups, taken straight from the
+// image rather than from a palette, so there is no atlas, font or dithering
+// involved. This is the usual way terminals show images at full colour.
+// Braille cells are thresholded instead, see `braille.rs`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockMode {
+    /// `▀`: top and bottom halves.
+    Half,
+    /// `▘▝▖▗▌▐▀▄▚▞▛▜▙▟`: 2 x 2 sub-pixels.
+    Quadrant,
+    /// The sextants of Symbols for Legacy Computing: 2 x 3 sub-pixels.
+    Sextant,
+    /// Thresholded 2 x 4 Braille dots, see [`crate::braille`].
+    Braille,
+}
+
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+impl BlockMode {
+    /// Sub-pixel columns and rows.
+    pub fn grid(self) -> (u32, u32) {
+        match self {
+            BlockMode::Half => (1, 2),
+            BlockMode::Quadrant => (2, 2),
+            BlockMode::Sextant => (2, 3),
+            BlockMode::Braille => (2, 4),
+        }
+    }
+
+    /// The character drawing the sub-pixels set in `pattern` in the
+    /// foreground. Bit `i` is sub-pixel `i` in reading order.
+    pub fn character(self, pattern: u8) -> char {
+        match self {
+            BlockMode::Half => [' ', '▀', '▄', '█'][pattern as usize & 3],
+            BlockMode::Quadrant => QUADRANTS[pattern as usize & 15],
+            BlockMode::Sextant => match pattern & 63 {
+                0 => ' ',
+                21 => '▌',
+                42 => '▐',
+                63 => '█',
+                // U+1FB00 onwards, in pattern order without the two halves.
+                p => {
+                    let index = p as u32 - 1 - (p > 21) as u32 - (p > 42) as u32;
+                    char::from_u32(0x1FB00 + index).unwrap()
+                }
+            },
+            BlockMode::Braille => braille_character(pattern),
+        }
+    }
+}
+
+/// One cell of a block render.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockCell {
+    /// Which sub-pixels are foreground, see [`BlockMode::character`].
+    pub pattern: u8,
+    pub foreground: Rgba<u8>,
+    pub background: Rgba<u8>,
+}
+
+impl BlockCell {
+    /// A transparent cell, left empty.
+    pub const EMPTY: BlockCell = BlockCell { pattern: 0, foreground: Rgba([0, 0, 0, 0]), background: Rgba([0, 0, 0, 0]) };
+}
+
+/// Cell size in pixels: `scale_y` high, and as wide as `cell_aspect`
+/// (default a typical terminal's `0.5`) makes it.
+pub fn block_cell_size(options: &RenderOptions) -> (u32, u32) {
+    let height = options.scale_y.round().max(1.0);
+    let width = (height * options.cell_aspect.unwrap_or(0.5)).round().max(1.0);
+    (width as u32, height as u32)
+}
+
+/// Start of sub-pixel `i` of `n` along a cell side of `size` pixels.
+pub(crate) fn split(size: u32, n: u32, i: u32) -> u32 {
+    size * i / n
+}
+
+/// The best split of the `kx` x `ky` cell at `(x, y)`, or `None` if the
+/// cell is transparent (with `options.transparency`).
+pub fn block_cell(img: &DynamicImage, mode: BlockMode, x: u32, y: u32, kx: u32, ky: u32, options: &RenderOptions) -> Option<BlockCell> {
+    let (columns, rows) = mode.grid();
+    let n = (columns * rows) as usize;
+
+    // Alpha-weighted (with transparency) sums per sub-pixel: weight, colour
+    // and squared colour.
+    let mut weights = [0.0f64; 8];
+    let mut sums = [[0.0f64; 3]; 8];
+    let mut squares = [0.0f64; 8];
+    let mut alpha = 0.0;
+    for row in 0..rows {
+        for column in 0..columns {
+            let i = (row * columns + column) as usize;
+            for py in y + split(ky, rows, row)..y + split(ky, rows, row + 1) {
+                for px in x + split(kx, columns, column)..x + split(kx, columns, column + 1) {
+                    let pixel = img.get_pixel(px, py);
+                    let a = pixel[3] as f64 / 255.0;
+                    alpha += a;
+                    let w = if options.transparency { a } else { 1.0 };
+                    weights[i] += w;
+                    for c in 0..3 {
+                        let v = pixel[c] as f64;
+                        sums[i][c] += w * v;
+                        squares[i] += w * v * v;
+                    }
+                }
+            }
+        }
+    }
+
+    if options.transparency && alpha / ((kx * ky).max(1) as f64) < options.alpha_threshold as f64 {
+        return None;
+    }
+
+    // Weight, colour sum and squared error of a group of sub-pixels.
+    let group = |pattern: u8| {
+        let (mut weight, mut sum, mut square) = (0.0, [0.0; 3], 0.0);
+        for i in (0..n).filter(|&i| pattern >> i & 1 == 1) {
+            weight += weights[i];
+            square += squares[i];
+            for c in 0..3 {
+                sum[c] += sums[i][c];
+            }
+        }
+        let error = match weight > 0.0 {
+            true => square - sum.iter().map(|s| s * s).sum::<f64>() / weight,
+            false => 0.0,
+        };
+        (weight, sum, error)
+    };
+    let mean = |(weight, sum, _): (f64, [f64; 3], f64)| match weight > 0.0 {
+        true => Rgba([
+            (sum[0] / weight).round() as u8,
+            (sum[1] / weight).round() as u8,
+            (sum[2] / weight).round() as u8,
+            255,
+        ]),
+        false => Rgba([0, 0, 0, 255]),
+    };
+
+    // A pattern and its complement are the same split with the colours
+    // swapped, so only patterns with the top left sub-pixel set are tried
+    // (plus the empty one for flat cells).
+    let all = ((1u32 << n) - 1) as u8;
+    let (pattern, _) = std::iter::once(0)
+        .chain((1..=all).filter(|p| p & 1 == 1))
+        .map(|p| (p, group(p).2 + group(all & !p).2))
+        .fold((0, f64::INFINITY), |best, (p, error)| match error < best.1 {
+            true => (p, error),
+            false => best,
+        });
+
+    // Draw whichever of the two groups is smaller in the foreground.
+    let pattern = match pattern.count_ones() * 2 > n as u32 {
+        true => all & !pattern,
+        false => pattern,
+    };
+    let background = mean(group(all & !pattern));
+    let foreground = match pattern {
+        0 => background,
+        _ => mean(group(pattern)),
+    };
+    Some(BlockCell { pattern, foreground, background })
+}
+
+/// Paints `cell` onto the `kx` x `ky` cell of `out` at `(x, y)`.
+pub fn paint_block(out: &mut RgbaImage, cell: &BlockCell, mode: BlockMode, x: u32, y: u32, kx: u32, ky: u32) {
+    let (columns, rows) = mode.grid();
+    for row in 0..rows {
+        for column in 0..columns {
+            let colour = match cell.pattern >> (row * columns + column) & 1 {
+                1 => cell.foreground,
+                _ => cell.background,
+            };
+            for py in y + split(ky, rows, row)..y + split(ky, rows, row + 1) {
+                for px in x + split(kx, columns, column)..x + split(kx, columns, column + 1) {
+                    out.put_pixel(px, py, colour);
+                }
+            }
+        }
+    }
+}
+
+/// The cells of `img` in block characters, row by row. Transparent cells
+/// are [`BlockCell::EMPTY`]. `progress` is called with (completed rows,
+/// total rows).
+pub fn block_cells(img: &DynamicImage, mode: BlockMode, options: &RenderOptions, progress: &mut dyn FnMut(u32, u32)) -> Vec<Vec<BlockCell>> {
+    init_thread_pool();
+
+    let preprocessed;
+    let img = match options.preprocess.as_slice() {
+        [] => img,
+        stages => {
+            preprocessed = preprocess::apply(img, stages);
+            &preprocessed
+        }
+    };
+
+    if mode == BlockMode::Braille {
+        return braille_cells(img, options, progress);
+    }
+
+    let (kx, ky) = block_cell_size(options);
+    let (width, height) = img.dimensions();
+    let rows: Vec<u32> = (0..height / ky).map(|row| row * ky).collect();
+    let columns: Vec<u32> = (0..width / kx).map(|column| column * kx).collect();
+    let total_rows = rows.len() as u32;
+
+    let mut cells = Vec::with_capacity(rows.len());
+    for (row, &y) in rows.iter().enumerate() {
+        cells.push(columns
+            .par_iter()
+            .map(|&x| block_cell(img, mode, x, y, kx, ky, options).unwrap_or(BlockCell::EMPTY))
+            .collect());
+
+        progress(row as u32 + 1, total_rows);
+    }
+
+    cells
+}
+
+/// Renders `img` in block characters, returning the picture and the cells
+/// it shows (see [`crate::text`] to write them out). `progress` is called
+/// with (completed rows, total rows).
+pub fn render_blocks(
+    img: &DynamicImage,
+    mode: BlockMode,
+    options: &RenderOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> (RgbaImage, Vec<Vec<BlockCell>>) {
+    let cells = block_cells(img, mode, options, progress);
+    let (kx, ky) = block_cell_size(options);
+    let (width, height) = img.dimensions();
+    let mut out: RgbaImage = ImageBuffer::new(width, height);
+
+    for (row, cells) in cells.iter().enumerate() {
+        for (column, cell) in cells.iter().enumerate() {
+            let (x, y) = (column as u32 * kx, row as u32 * ky);
+            match mode {
+                BlockMode::Braille => paint_braille(&mut out, cell, x, y, kx, ky),
+                mode => paint_block(&mut out, cell, mode, x, y, kx, ky),
+            }
+        }
+    }
+
+    (out, cells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sextant_characters() {
+        let mode = BlockMode::Sextant;
+        assert_eq!(mode.character(0), ' ');
+        assert_eq!(mode.character(1), '\u{1FB00}');
+        assert_eq!(mode.character(20), '\u{1FB13}');
+        assert_eq!(mode.character(22), '\u{1FB14}');
+        assert_eq!(mode.character(41), '\u{1FB27}');
+        assert_eq!(mode.character(43), '\u{1FB28}');
+        assert_eq!(mode.character(62), '\u{1FB3B}');
+        assert_eq!(mode.character(63), '█');
+    }
+
+    #[test]
+    fn splits_two_colours() {
+        const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+        const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+        // Left column blue, right column red.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 12, |x, _| if x >= 4 { RED } else { BLUE }));
+        let cell = block_cell(&img, BlockMode::Sextant, 0, 0, 8, 12, &RenderOptions::default()).unwrap();
+        assert_eq!(BlockMode::Sextant.character(cell.pattern), '▌');
+        assert_eq!((cell.foreground, cell.background), (BLUE, RED));
+
+        let mut out = RgbaImage::new(8, 12);
+        paint_block(&mut out, &cell, BlockMode::Sextant, 0, 0, 8, 12);
+        assert_eq!(out.as_raw(), img.to_rgba8().as_raw());
+    }
+}
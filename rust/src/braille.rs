@@ -0,0 +1,198 @@
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+use crate::blocks::{block_cell_size, split, BlockCell};
+use crate::dither;
+use crate::preprocess::luma;
+use crate::FontImageTransformer::RenderOptions;
+
+// Braille dot-matrix rendering.
+//
+// Each cell holds a 2 x 4 Braille pattern (U+2800 - U+28FF), one dot per
+// sub-pixel of the tile. A dot is raised where its sub-pixel is brighter than
+// `braille_threshold` (darker with `braille_invert`). The `dither` option
+// works on the dot grid rather than on cells, so gradients come out as dot
+// density. Unlike the block modes there is no background colour: the dots
+// are drawn over whatever the text sits on, optionally in the mean colour of
+// the pixels under them.
+
+const COLUMNS: u32 = 2;
+const ROWS: u32 = 4;
+
+/// Braille dot (bit of the codepoint) of each sub-pixel in reading order.
+const DOTS: [u8; 8] = [0, 3, 1, 4, 2, 5, 6, 7];
+
+/// The Braille character raising the dots set in `pattern`, bit `i` being
+/// sub-pixel `i` in reading order.
+pub fn braille_character(pattern: u8) -> char {
+    let bits = DOTS.iter()
+        .enumerate()
+        .filter(|&(i, _)| pattern >> i & 1 == 1)
+        .fold(0, |bits, (_, &dot)| bits | 1 << dot);
+    char::from_u32(0x2800 + bits).unwrap()
+}
+
+/// The pixels under one dot: mean luminance (`0..1`) and alpha, colour sum
+/// and pixel count.
+#[derive(Clone, Copy, Default)]
+struct Dot {
+    luma: f32,
+    alpha: f32,
+    colour: [f32; 3],
+    pixels: f32,
+}
+
+fn sample(img: &DynamicImage, x0: u32, x1: u32, y0: u32, y1: u32) -> Dot {
+    let mut dot = Dot::default();
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let p = img.get_pixel(x, y);
+            dot.luma += luma(&p) / 255.0;
+            dot.alpha += p[3] as f32 / 255.0;
+            for c in 0..3 {
+                dot.colour[c] += p[c] as f32;
+            }
+            dot.pixels += 1.0;
+        }
+    }
+    if dot.pixels > 0.0 {
+        dot.luma /= dot.pixels;
+        dot.alpha /= dot.pixels;
+    }
+    dot
+}
+
+/// The Braille pattern and dot colour of every cell of `img`, row by row.
+/// `progress` is called with (completed rows, total rows).
+pub fn braille_cells(img: &DynamicImage, options: &RenderOptions, progress: &mut dyn FnMut(u32, u32)) -> Vec<Vec<BlockCell>> {
+    let (kx, ky) = block_cell_size(options);
+    let (width, height) = img.dimensions();
+    let (columns, rows) = (width / kx, height / ky);
+    let (dots_x, dots_y) = ((columns * COLUMNS) as usize, (rows * ROWS) as usize);
+
+    let dots: Vec<Dot> = (0..dots_y)
+        .into_par_iter()
+        .flat_map_iter(|dy| {
+            (0..dots_x).map(move |dx| {
+                let (x, y) = (dx as u32 / COLUMNS * kx, dy as u32 / ROWS * ky);
+                let (sx, sy) = (dx as u32 % COLUMNS, dy as u32 % ROWS);
+                sample(
+                    img,
+                    x + split(kx, COLUMNS, sx),
+                    x + split(kx, COLUMNS, sx + 1),
+                    y + split(ky, ROWS, sy),
+                    y + split(ky, ROWS, sy + 1),
+                )
+            })
+        })
+        .collect();
+
+    let bayer = options.dither.bayer_size().map(|n| (n, dither::bayer_matrix(n)));
+    let kernel = dither::diffusion_kernel(options.dither);
+    let mut error = vec![0.0f32; dots.len()];
+    let mut raised = vec![false; dots.len()];
+    // Plain dots are white, or black when they mark the dark pixels.
+    let plain = match options.braille_invert {
+        false => Rgba([255, 255, 255, 255]),
+        true => Rgba([0, 0, 0, 255]),
+    };
+
+    let mut cells = Vec::with_capacity(rows as usize);
+    for row in 0..rows as usize {
+        // Dots are thresholded in scan order, each carrying its residual on
+        // to the dots after it.
+        for dy in row * ROWS as usize..(row + 1) * ROWS as usize {
+            for dx in 0..dots_x {
+                let i = dy * dots_x + dx;
+                if options.transparency && dots[i].alpha < options.alpha_threshold {
+                    continue;
+                }
+
+                let mut value = dots[i].luma + error[i];
+                if let Some((n, thresholds)) = &bayer {
+                    value += thresholds[(dy % n) * n + dx % n] * options.dither_strength;
+                }
+                let level = if value > options.braille_threshold { 1.0 } else { 0.0 };
+                raised[i] = (level == 1.0) != options.braille_invert;
+
+                let residual = ((dots[i].luma + error[i]).clamp(0.0, 1.0) - level) * options.dither_strength;
+                for &(ox, oy, weight) in kernel {
+                    let (x, y) = (dx as i64 + ox as i64, dy as i64 + oy as i64);
+                    if x >= 0 && (x as usize) < dots_x && (y as usize) < dots_y {
+                        error[y as usize * dots_x + x as usize] += residual * weight;
+                    }
+                }
+            }
+        }
+
+        let cell_row = (0..columns as usize)
+            .map(|column| {
+                let (mut pattern, mut colour, mut pixels) = (0u8, [0.0f32; 3], 0.0);
+                for bit in 0..(COLUMNS * ROWS) as usize {
+                    let dy = row * ROWS as usize + bit / COLUMNS as usize;
+                    let dx = column * COLUMNS as usize + bit % COLUMNS as usize;
+                    let dot = &dots[dy * dots_x + dx];
+                    if raised[dy * dots_x + dx] {
+                        pattern |= 1 << bit;
+                        pixels += dot.pixels;
+                        for (sum, v) in colour.iter_mut().zip(dot.colour) {
+                            *sum += v;
+                        }
+                    }
+                }
+
+                let foreground = match options.braille_colour && pixels > 0.0 {
+                    true => Rgba([
+                        (colour[0] / pixels).round() as u8,
+                        (colour[1] / pixels).round() as u8,
+                        (colour[2] / pixels).round() as u8,
+                        255,
+                    ]),
+                    false => plain,
+                };
+                BlockCell { pattern, foreground, background: Rgba([0, 0, 0, 0]) }
+            })
+            .collect();
+        cells.push(cell_row);
+
+        progress(row as u32 + 1, rows);
+    }
+
+    cells
+}
+
+/// Paints the raised dots of `cell` as discs onto the `kx` x `ky` cell of
+/// `out` at `(x, y)`.
+pub fn paint_braille(out: &mut RgbaImage, cell: &BlockCell, x: u32, y: u32, kx: u32, ky: u32) {
+    for bit in (0..COLUMNS * ROWS).filter(|&bit| cell.pattern >> bit & 1 == 1) {
+        let (sx, sy) = (bit % COLUMNS, bit / COLUMNS);
+        let (x0, x1) = (x + split(kx, COLUMNS, sx), x + split(kx, COLUMNS, sx + 1));
+        let (y0, y1) = (y + split(ky, ROWS, sy), y + split(ky, ROWS, sy + 1));
+
+        let (cx, cy) = ((x0 + x1) as f32 / 2.0, (y0 + y1) as f32 / 2.0);
+        let radius = ((x1 - x0).min(y1 - y0) as f32 * 0.4).max(0.5);
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let (dx, dy) = (px as f32 + 0.5 - cx, py as f32 + 0.5 - cy);
+                if dx * dx + dy * dy <= radius * radius {
+                    out.put_pixel(px, py, cell.foreground);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_order() {
+        assert_eq!(braille_character(0), '\u{2800}');
+        // Left column top to bottom: dots 1, 2, 3, 7.
+        assert_eq!(braille_character(0b0101_0101), '\u{2847}');
+        // Right column: dots 4, 5, 6, 8.
+        assert_eq!(braille_character(0b1010_1010), '\u{28B8}');
+        assert_eq!(braille_character(0xFF), '\u{28FF}');
+    }
+}
@@ -0,0 +1,247 @@
+use serde::Deserialize;
+
+// Colour space conversions. Channels are `f32`; sRGB and linear RGB are in
+// `0.0..=1.0`, Lab uses the usual L in `0..=100` with a D65 white point.
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn rgb8_to_linear(rgb: [u8; 3]) -> [f32; 3] {
+    rgb.map(|v| srgb_to_linear(v as f32 / 255.0))
+}
+
+pub fn linear_to_rgb8(rgb: [f32; 3]) -> [u8; 3] {
+    rgb.map(|v| (linear_to_srgb(v.clamp(0.0, 1.0)) * 255.0).round() as u8)
+}
+
+const WHITE: [f32; 3] = [0.95047, 1.0, 1.08883];
+
+fn lab_f(t: f32) -> f32 {
+    if t > 216.0 / 24389.0 {
+        t.cbrt()
+    } else {
+        (24389.0 / 27.0 * t + 16.0) / 116.0
+    }
+}
+
+fn lab_f_inv(t: f32) -> f32 {
+    if t * t * t > 216.0 / 24389.0 {
+        t * t * t
+    } else {
+        (116.0 * t - 16.0) / (24389.0 / 27.0)
+    }
+}
+
+pub fn linear_to_xyz([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        0.0193339 * r + 0.119192 * g + 0.9503041 * b,
+    ]
+}
+
+pub fn xyz_to_linear([x, y, z]: [f32; 3]) -> [f32; 3] {
+    [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.969266 * x + 1.8760108 * y + 0.041556 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+}
+
+pub fn linear_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let xyz = linear_to_xyz(rgb);
+    let [fx, fy, fz] = [0, 1, 2].map(|i| lab_f(xyz[i] / WHITE[i]));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+pub fn lab_to_linear([l, a, b]: [f32; 3]) -> [f32; 3] {
+    let fy = (l + 16.0) / 116.0;
+    let f = [fy + a / 500.0, fy, fy - b / 200.0];
+    xyz_to_linear([0, 1, 2].map(|i| lab_f_inv(f[i]) * WHITE[i]))
+}
+
+pub fn rgb8_to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    linear_to_lab(rgb8_to_linear(rgb))
+}
+
+pub fn lab_to_rgb8(lab: [f32; 3]) -> [u8; 3] {
+    linear_to_rgb8(lab_to_linear(lab))
+}
+
+// OKLab (Björn Ottosson), from linear sRGB. L is in `0..=1`.
+pub fn linear_to_oklab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let l = (0.41222146 * r + 0.53633255 * g + 0.05144599 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171885 * g + 0.6299787 * b).cbrt();
+    [
+        0.21045426 * l + 0.7936178 * m - 0.00407205 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.02590404 * l + 0.78277177 * m - 0.80867577 * s,
+    ]
+}
+
+pub fn oklab_to_linear([l, a, b]: [f32; 3]) -> [f32; 3] {
+    let l_ = l + 0.39633778 * a + 0.21580376 * b;
+    let m_ = l - 0.10556135 * a - 0.06385417 * b;
+    let s_ = l - 0.08948418 * a - 1.2914855 * b;
+    let (l, m, s) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+    [
+        4.0767417 * l - 3.3077116 * m + 0.23096994 * s,
+        -1.268438 * l + 2.6097574 * m - 0.34131938 * s,
+        -0.0041960864 * l - 0.7034186 * m + 1.7076147 * s,
+    ]
+}
+
+/// OKLab to lightness, chroma and hue (radians).
+pub fn oklab_to_oklch([l, a, b]: [f32; 3]) -> [f32; 3] {
+    [l, a.hypot(b), b.atan2(a)]
+}
+
+pub fn oklch_to_oklab([l, c, h]: [f32; 3]) -> [f32; 3] {
+    [l, c * h.cos(), c * h.sin()]
+}
+
+/// The space tiles and glyphs are compared (and palettes quantized) in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum ColourSpace {
+    #[serde(rename = "srgb")]
+    Srgb,
+    #[serde(rename = "linear")]
+    Linear,
+    #[serde(rename = "lab")]
+    Lab,
+    #[serde(rename = "oklab")]
+    Oklab,
+}
+
+impl ColourSpace {
+    pub fn from_name(name: &str) -> Option<ColourSpace> {
+        match name {
+            "srgb" => Some(ColourSpace::Srgb),
+            "linear" => Some(ColourSpace::Linear),
+            "lab" => Some(ColourSpace::Lab),
+            "oklab" => Some(ColourSpace::Oklab),
+            _ => None,
+        }
+    }
+
+    // Every space is scaled so its lightness spans 0..255 like an sRGB byte,
+    // which keeps distances normalised by 255 comparable between spaces.
+    fn scale(self) -> f32 {
+        match self {
+            ColourSpace::Srgb | ColourSpace::Linear | ColourSpace::Oklab => 255.0,
+            ColourSpace::Lab => 2.55,
+        }
+    }
+
+    /// `rgb` in this space, see [`ColourSpace::decode`] for the way back.
+    pub fn encode(self, rgb: [u8; 3]) -> [f32; 3] {
+        let v = match self {
+            ColourSpace::Srgb => return rgb.map(|c| c as f32),
+            ColourSpace::Linear => rgb8_to_linear(rgb),
+            ColourSpace::Lab => rgb8_to_lab(rgb),
+            ColourSpace::Oklab => linear_to_oklab(rgb8_to_linear(rgb)),
+        };
+        v.map(|c| c * self.scale())
+    }
+
+    pub fn decode(self, v: [f32; 3]) -> [u8; 3] {
+        let v = v.map(|c| c / self.scale());
+        match self {
+            ColourSpace::Srgb => v.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8),
+            ColourSpace::Linear => linear_to_rgb8(v),
+            ColourSpace::Lab => lab_to_rgb8(v),
+            ColourSpace::Oklab => linear_to_rgb8(oklab_to_linear(v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: [f32; 3], b: [f32; 3], tolerance: f32) -> bool {
+        a.iter().zip(&b).all(|(a, b)| (a - b).abs() <= tolerance)
+    }
+
+    /// Every 15th value of every channel.
+    fn colours() -> impl Iterator<Item = [u8; 3]> {
+        (0..=255u8).step_by(15).flat_map(|r| (0..=255u8).step_by(15).flat_map(move |g| (0..=255u8).step_by(15).map(move |b| [r, g, b])))
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        for v in 0..=255u8 {
+            assert_eq!(linear_to_rgb8(rgb8_to_linear([v, v, v])), [v, v, v]);
+        }
+        for i in 0..=1000 {
+            let v = i as f32 / 1000.0;
+            assert!((linear_to_srgb(srgb_to_linear(v)) - v).abs() < 1e-5, "{}", v);
+        }
+        // The linear segment meets the curve.
+        assert!((srgb_to_linear(0.04045) - ((0.04045f32 + 0.055) / 1.055).powf(2.4)).abs() < 1e-6);
+        assert!((srgb_to_linear(0.5) - 0.21404).abs() < 1e-4);
+    }
+
+    #[test]
+    fn lab_reference_values() {
+        assert!(close(rgb8_to_lab([255, 255, 255]), [100.0, 0.0, 0.0], 0.01), "{:?}", rgb8_to_lab([255; 3]));
+        assert!(close(rgb8_to_lab([0, 0, 0]), [0.0, 0.0, 0.0], 0.01));
+        assert!(close(rgb8_to_lab([255, 0, 0]), [53.24, 80.09, 67.20], 0.05), "{:?}", rgb8_to_lab([255, 0, 0]));
+        assert!(close(rgb8_to_lab([0, 0, 255]), [32.30, 79.19, -107.86], 0.05), "{:?}", rgb8_to_lab([0, 0, 255]));
+        // Greys have no chroma.
+        let [_, a, b] = rgb8_to_lab([119, 119, 119]);
+        assert!(a.abs() < 0.01 && b.abs() < 0.01);
+    }
+
+    #[test]
+    fn oklab_reference_values() {
+        let oklab = |rgb| linear_to_oklab(rgb8_to_linear(rgb));
+        assert!(close(oklab([255, 255, 255]), [1.0, 0.0, 0.0], 1e-4), "{:?}", oklab([255; 3]));
+        assert!(close(oklab([0, 0, 0]), [0.0, 0.0, 0.0], 1e-6));
+        assert!(close(oklab([255, 0, 0]), [0.62796, 0.22486, 0.12585], 1e-3), "{:?}", oklab([255, 0, 0]));
+
+        for rgb in colours() {
+            let lab = oklab(rgb);
+            assert!(close(oklch_to_oklab(oklab_to_oklch(lab)), lab, 1e-5));
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for rgb in colours() {
+            let linear = rgb8_to_linear(rgb);
+            assert!(close(xyz_to_linear(linear_to_xyz(linear)), linear, 1e-5));
+            assert!(close(lab_to_linear(linear_to_lab(linear)), linear, 1e-4));
+            assert!(close(oklab_to_linear(linear_to_oklab(linear)), linear, 1e-4));
+            assert_eq!(lab_to_rgb8(rgb8_to_lab(rgb)), rgb);
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for space in [ColourSpace::Srgb, ColourSpace::Linear, ColourSpace::Lab, ColourSpace::Oklab] {
+            for rgb in colours() {
+                assert_eq!(space.decode(space.encode(rgb)), rgb, "{:?} {:?}", space, rgb);
+            }
+            // Lightness spans 0..255 in every space.
+            assert!((space.encode([255; 3])[0] - 255.0).abs() < 0.05, "{:?}", space);
+            assert!(space.encode([0; 3])[0].abs() < 1e-3);
+        }
+        assert_eq!(ColourSpace::from_name("oklab"), Some(ColourSpace::Oklab));
+        assert_eq!(ColourSpace::from_name("hsv"), None);
+    }
+}
@@ -0,0 +1,91 @@
+use image::{imageops, Rgba, RgbaImage};
+use rusttype::{point, GlyphId, Scale};
+
+use crate::atlas::GlyphAtlas;
+use crate::palette::format_hex;
+use crate::fonts::FontStack;
+
+// Debug contact sheet.
+//
+// Every candidate glyph render of an atlas on one image, enlarged, with the
+// codepoint (or glyph id when the font doesn't map one) and its foreground
+// and background colour written underneath. Labels are set in the font
+// stack.
+
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const LABEL: Rgba<u8> = Rgba([220, 220, 220, 255]);
+const LABEL_SIZE: f32 = 11.0;
+const LINE: u32 = 13;
+const PADDING: u32 = 6;
+const MIN_TILE_WIDTH: u32 = 136;
+
+/// Renders the contact sheet for `atlas`, which was built from `fonts`.
+pub fn contact_sheet(atlas: &GlyphAtlas, fonts: &FontStack) -> RgbaImage {
+    let codepoints = fonts.codepoints();
+
+    // Enlarge small cells to roughly 48 pixels high.
+    let zoom = 48u32.div_ceil(atlas.cell.height).max(1);
+    let (glyph_width, glyph_height) = (atlas.cell.width * zoom, atlas.cell.height * zoom);
+    let tile_width = glyph_width.max(MIN_TILE_WIDTH) + PADDING;
+    let tile_height = glyph_height + 3 * LINE + PADDING;
+
+    // Roughly square sheet.
+    let count = atlas.glyphs.len().max(1) as f64;
+    let columns = ((count * tile_height as f64 / tile_width as f64).sqrt().ceil() as u32).max(1);
+    let rows = (atlas.glyphs.len() as u32).div_ceil(columns).max(1);
+
+    let mut sheet = RgbaImage::from_pixel(columns * tile_width + PADDING, rows * tile_height + PADDING, BACKGROUND);
+    for (i, (glyph, render)) in atlas.glyphs.iter().zip(&atlas.renders).enumerate() {
+        let x = (i as u32 % columns) * tile_width + PADDING;
+        let y = (i as u32 / columns) * tile_height + PADDING;
+
+        let enlarged = imageops::resize(glyph, glyph_width, glyph_height, imageops::FilterType::Nearest);
+        imageops::overlay(&mut sheet, &enlarged, x as i64, y as i64);
+
+        let mut name = render.key().label(&codepoints);
+        if render.subpixel > 0 {
+            name += &format!(" +{}", render.subpixel);
+        }
+
+        let text_y = y + glyph_height;
+        draw_text(&mut sheet, fonts, &name, x, text_y);
+        match (render.foreground, render.background) {
+            (Some(fg), Some(bg)) => {
+                draw_text(&mut sheet, fonts, &format!("fg {}", format_hex(Rgba(fg))), x, text_y + LINE);
+                draw_text(&mut sheet, fonts, &format!("bg {}", format_hex(Rgba(bg))), x, text_y + 2 * LINE);
+            }
+            _ => draw_text(&mut sheet, fonts, "mask", x, text_y + LINE),
+        }
+    }
+
+    sheet
+}
+
+fn draw_text(sheet: &mut RgbaImage, fonts: &FontStack, text: &str, x: u32, y: u32) {
+    let scale = Scale::uniform(LABEL_SIZE);
+    let ascent = fonts.primary().v_metrics(scale).ascent;
+
+    // Each character from the first font that has it, so fallback glyphs
+    // are labelled legibly.
+    let mut pen_x = x as f32;
+    for c in text.chars() {
+        let (font, id) = fonts.resolve(c).unwrap_or((0, 0));
+        let glyph = fonts.fonts()[font as usize].glyph(GlyphId(id)).scaled(scale);
+        let advance = glyph.h_metrics().advance_width;
+        let glyph = glyph.positioned(point(pen_x, y as f32 + ascent));
+        pen_x += advance;
+
+        let Some(bb) = glyph.pixel_bounding_box() else {
+            continue;
+        };
+        glyph.draw(|gx, gy, v| {
+            let (px, py) = (bb.min.x + gx as i32, bb.min.y + gy as i32);
+            if px >= 0 && py >= 0 && (px as u32) < sheet.width() && (py as u32) < sheet.height() {
+                let pixel = sheet.get_pixel_mut(px as u32, py as u32);
+                for c in 0..3 {
+                    pixel[c] = (pixel[c] as f32 + (LABEL[c] as f32 - pixel[c] as f32) * v) as u8;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::fonts::glyph_label;
+use crate::style::GlyphStyle;
+use crate::variant::GlyphVariant;
+
+// Glyph deduplication.
+//
+// Full fonts carry many glyphs that rasterise identically at small sizes
+// (Latin / Greek / Cyrillic look-alikes, precomposed forms whose marks
+// vanish, blanks). Every one of them is painted onto every colour pair and
+// scored against every cell, for no change in the output. Glyphs whose
+// coverage is identical to an earlier glyph's are always dropped; with
+// `prune_distance` glyphs that are merely close to an earlier one are too.
+// Earlier glyphs win, which is also what the matcher does on ties, so exact
+// deduplication leaves renders byte-identical.
+
+/// A glyph of the font stack in some style, possibly turned or mirrored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GlyphKey {
+    /// Position of the font in the stack.
+    pub font: u8,
+    pub glyph: u16,
+    pub style: GlyphStyle,
+    pub variant: Option<GlyphVariant>,
+}
+
+impl GlyphKey {
+    /// [`glyph_label`] followed by the style and variant, if any.
+    pub fn label(&self, codepoints: &[HashMap<u16, char>]) -> String {
+        let mut label = glyph_label(self.font, self.glyph, codepoints);
+        if self.style != GlyphStyle::Regular {
+            label = format!("{} {}", label, self.style.name());
+        }
+        match self.variant {
+            None => label,
+            Some(variant) => format!("{} {}", label, variant.name()),
+        }
+    }
+}
+
+/// One glyph's coverage over all of its sub-pixel renders.
+pub struct GlyphMask {
+    pub key: GlyphKey,
+    /// Coverage (`0..1`) of every sub-pixel render, cell after cell.
+    pub coverage: Vec<f32>,
+    /// Which pixels the rasteriser touched. Part of what makes two glyphs
+    /// identical, as it decides which pixels the white mask paints.
+    pub drawn: Vec<bool>,
+}
+
+/// A glyph that was left out of the atlas in favour of `into`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GlyphMerge {
+    pub glyph: GlyphKey,
+    pub into: GlyphKey,
+    /// Mean per-pixel coverage difference, `0` for exact duplicates.
+    pub distance: f32,
+}
+
+/// Coverage bits and drawn pixels.
+type ExactKey<'a> = (Vec<u32>, &'a [bool]);
+
+/// Which of `masks` to keep, and what the others were merged into. A glyph
+/// is merged into the closest kept glyph at most `max_distance` away.
+pub fn distinct(masks: &[GlyphMask], max_distance: f32) -> (Vec<bool>, Vec<GlyphMerge>) {
+    let mut exact: HashMap<ExactKey, GlyphKey> = HashMap::new();
+    // Kept glyphs and their total coverage.
+    let mut kept: Vec<(&GlyphMask, f32)> = Vec::new();
+    let mut keep = Vec::with_capacity(masks.len());
+    let mut merges = Vec::new();
+
+    for mask in masks {
+        let key = (mask.coverage.iter().map(|v| v.to_bits()).collect(), mask.drawn.as_slice());
+        if let Some(&into) = exact.get(&key) {
+            merges.push(GlyphMerge { glyph: mask.key, into, distance: 0.0 });
+            keep.push(false);
+            continue;
+        }
+
+        let ink: f32 = mask.coverage.iter().sum();
+        if max_distance > 0.0 {
+            if let Some((other, distance)) = nearest(mask, ink, &kept, max_distance) {
+                merges.push(GlyphMerge { glyph: mask.key, into: other.key, distance });
+                keep.push(false);
+                continue;
+            }
+        }
+
+        exact.insert(key, mask.key);
+        kept.push((mask, ink));
+        keep.push(true);
+    }
+
+    (keep, merges)
+}
+
+fn nearest<'a>(mask: &GlyphMask, ink: f32, kept: &[(&'a GlyphMask, f32)], max_distance: f32) -> Option<(&'a GlyphMask, f32)> {
+    let len = mask.coverage.len().max(1) as f32;
+    let limit = max_distance * len;
+    let mut best: Option<(&GlyphMask, f32)> = None;
+
+    for &(other, other_ink) in kept {
+        // The difference in total ink bounds the summed difference from below.
+        if other.coverage.len() != mask.coverage.len() || (ink - other_ink).abs() > limit {
+            continue;
+        }
+
+        let mut sum = 0.0;
+        for (a, b) in mask.coverage.iter().zip(&other.coverage) {
+            sum += (a - b).abs();
+            if sum > limit {
+                break;
+            }
+        }
+
+        if sum <= limit && sum / len < best.map_or(f32::INFINITY, |(_, d)| d) {
+            best = Some((other, sum / len));
+        }
+    }
+
+    best
+}
+
+/// One line per merged glyph, labelled with `codepoints` (see
+/// [`crate::fonts::FontStack::codepoints`]).
+pub fn report(merges: &[GlyphMerge], codepoints: &[HashMap<u16, char>]) -> String {
+    merges
+        .iter()
+        .map(|m| format!("{} -> {} ({:.4})\n", m.glyph.label(codepoints), m.into.label(codepoints), m.distance))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(font: u8, glyph: u16, coverage: &[f32]) -> GlyphMask {
+        GlyphMask {
+            key: GlyphKey { font, glyph, style: GlyphStyle::Regular, variant: None },
+            coverage: coverage.to_vec(),
+            drawn: coverage.iter().map(|&v| v > 0.0).collect(),
+        }
+    }
+
+    #[test]
+    fn merges_exact_duplicates() {
+        let masks = [
+            mask(0, 1, &[0.0, 0.5, 1.0, 0.0]),
+            mask(0, 2, &[0.0, 0.5, 1.0, 0.0]),
+            mask(0, 3, &[0.0, 0.5, 0.9, 0.0]),
+        ];
+        let (keep, merges) = distinct(&masks, 0.0);
+        assert_eq!(keep, [true, false, true]);
+        assert_eq!(merges, [GlyphMerge { glyph: masks[1].key, into: masks[0].key, distance: 0.0 }]);
+
+        // Same coverage, but the rasteriser touched another pixel.
+        let mut touched = mask(0, 4, &[0.0, 0.5, 1.0, 0.0]);
+        touched.drawn[0] = true;
+        assert_eq!(distinct(&[mask(0, 1, &[0.0, 0.5, 1.0, 0.0]), touched], 0.0).0, [true, true]);
+    }
+
+    #[test]
+    fn merges_within_prune_distance() {
+        let masks = [
+            mask(0, 1, &[0.0, 0.0, 1.0, 1.0]),
+            mask(0, 2, &[1.0, 1.0, 0.0, 0.0]),
+            // 0.1 from the first on average, 0.9 from the second.
+            mask(0, 3, &[0.0, 0.0, 1.0, 0.6]),
+            // 0.25 from the first.
+            mask(0, 4, &[0.0, 0.0, 1.0, 0.0]),
+        ];
+
+        let (keep, merges) = distinct(&masks, 0.2);
+        assert_eq!(keep, [true, true, false, true]);
+        assert_eq!(merges.len(), 1);
+        assert_eq!((merges[0].glyph, merges[0].into), (masks[2].key, masks[0].key));
+        assert!((merges[0].distance - 0.1).abs() < 1e-6);
+
+        assert_eq!(distinct(&masks, 0.3).0, [true, true, false, false]);
+        assert_eq!(distinct(&masks, 0.0).0, [true, true, true, true]);
+    }
+
+    #[test]
+    fn earlier_glyphs_win() {
+        // The primary font's glyph comes first, the fallback's is merged.
+        let masks = [mask(0, 7, &[0.2, 0.8]), mask(1, 3, &[0.2, 0.8]), mask(0, 9, &[0.25, 0.8])];
+        let (keep, merges) = distinct(&masks, 0.1);
+        assert_eq!(keep, [true, false, false]);
+        assert!(merges.iter().all(|merge| merge.into == masks[0].key));
+    }
+}
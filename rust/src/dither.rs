@@ -0,0 +1,193 @@
+use serde::Deserialize;
+
+use crate::kernel::{PackedTile, CHANNELS};
+
+// Dithering across the cell grid.
+//
+// Each cell is matched to a single glyph render, so a small palette leaves a
+// residual between the tile's average colour and the chosen glyph's. Error
+// diffusion carries that residual into the tiles that haven't been matched
+// yet; ordered dithering instead biases every tile by a Bayer threshold.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Dither {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "floyd-steinberg")]
+    FloydSteinberg,
+    #[serde(rename = "atkinson")]
+    Atkinson,
+    #[serde(rename = "sierra")]
+    Sierra,
+    #[serde(rename = "bayer-2")]
+    Bayer2,
+    #[serde(rename = "bayer-4")]
+    Bayer4,
+    #[serde(rename = "bayer-8")]
+    Bayer8,
+}
+
+impl Dither {
+    /// Error diffusion needs the cells matched in scan order.
+    pub fn is_error_diffusion(self) -> bool {
+        !diffusion_kernel(self).is_empty()
+    }
+
+    pub fn bayer_size(self) -> Option<usize> {
+        match self {
+            Dither::Bayer2 => Some(2),
+            Dither::Bayer4 => Some(4),
+            Dither::Bayer8 => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// (dx, dy, weight) of each neighbour the residual is pushed to.
+pub fn diffusion_kernel(dither: Dither) -> &'static [(i32, i32, f32)] {
+    match dither {
+        Dither::FloydSteinberg => &[
+            (1, 0, 7.0 / 16.0),
+            (-1, 1, 3.0 / 16.0), (0, 1, 5.0 / 16.0), (1, 1, 1.0 / 16.0),
+        ],
+        // Atkinson only passes on 6/8 of the error.
+        Dither::Atkinson => &[
+            (1, 0, 1.0 / 8.0), (2, 0, 1.0 / 8.0),
+            (-1, 1, 1.0 / 8.0), (0, 1, 1.0 / 8.0), (1, 1, 1.0 / 8.0),
+            (0, 2, 1.0 / 8.0),
+        ],
+        Dither::Sierra => &[
+            (1, 0, 5.0 / 32.0), (2, 0, 3.0 / 32.0),
+            (-2, 1, 2.0 / 32.0), (-1, 1, 4.0 / 32.0), (0, 1, 5.0 / 32.0), (1, 1, 4.0 / 32.0), (2, 1, 2.0 / 32.0),
+            (-1, 2, 2.0 / 32.0), (0, 2, 3.0 / 32.0), (1, 2, 2.0 / 32.0),
+        ],
+        _ => &[],
+    }
+}
+
+/// Normalised `n` x `n` Bayer thresholds in `-0.5..0.5`, row-major. `n` must
+/// be a power of two.
+pub fn bayer_matrix(n: usize) -> Vec<f32> {
+    let mut m = vec![0u32];
+    let mut size = 1;
+    while size < n {
+        let mut next = vec![0; size * size * 4];
+        for y in 0..size {
+            for x in 0..size {
+                let v = 4 * m[y * size + x];
+                next[y * 2 * size + x] = v;
+                next[y * 2 * size + x + size] = v + 2;
+                next[(y + size) * 2 * size + x] = v + 3;
+                next[(y + size) * 2 * size + x + size] = v + 1;
+            }
+        }
+        m = next;
+        size *= 2;
+    }
+
+    let count = (size * size) as f32;
+    m.iter().map(|&v| (v as f32 + 0.5) / count - 0.5).collect()
+}
+
+/// Per-cell colour error waiting to be applied, one RGB triple per cell.
+pub struct ErrorGrid {
+    cols: usize,
+    rows: usize,
+    error: Vec<[f32; CHANNELS]>,
+}
+
+impl ErrorGrid {
+    pub fn new(cols: usize, rows: usize) -> ErrorGrid {
+        ErrorGrid { cols, rows, error: vec![[0.0; CHANNELS]; cols * rows] }
+    }
+
+    pub fn get(&self, col: usize, row: usize) -> [f32; CHANNELS] {
+        self.error[row * self.cols + col]
+    }
+
+    /// Spreads `residual` from (`col`, `row`) to its neighbours.
+    pub fn diffuse(&mut self, dither: Dither, col: usize, row: usize, residual: [f32; CHANNELS]) {
+        for &(dx, dy, weight) in diffusion_kernel(dither) {
+            let (c, r) = (col as i64 + dx as i64, row as i64 + dy as i64);
+            if c < 0 || r < 0 || c as usize >= self.cols || r as usize >= self.rows {
+                continue;
+            }
+
+            let cell = &mut self.error[r as usize * self.cols + c as usize];
+            for (e, v) in cell.iter_mut().zip(residual) {
+                *e += v * weight;
+            }
+        }
+    }
+}
+
+/// Average colour of a tile.
+pub fn mean_colour(tile: &PackedTile) -> [f32; CHANNELS] {
+    let mut sum = [0.0; CHANNELS];
+    for p in tile.data.chunks_exact(CHANNELS) {
+        for (s, &v) in sum.iter_mut().zip(p) {
+            *s += v as f32;
+        }
+    }
+
+    let pixels = (tile.data.len() / CHANNELS).max(1) as f32;
+    sum.map(|s| s / pixels)
+}
+
+/// Shifts every pixel of `tile` by `offset`, clamping to the byte range.
+pub fn offset_tile(tile: &mut PackedTile, offset: [f32; CHANNELS]) {
+    for p in tile.data.chunks_exact_mut(CHANNELS) {
+        for (v, o) in p.iter_mut().zip(offset) {
+            *v = (*v as f32 + o).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_thresholds_are_a_centred_permutation() {
+        assert_eq!(bayer_matrix(2), [-0.375, 0.125, 0.375, -0.125]);
+        for n in [2, 4, 8] {
+            let m = bayer_matrix(n);
+            let count = n * n;
+            // Every rank 0..count appears exactly once.
+            let mut ranks: Vec<usize> = m.iter().map(|&v| ((v + 0.5) * count as f32 - 0.5).round() as usize).collect();
+            ranks.sort_unstable();
+            assert_eq!(ranks, (0..count).collect::<Vec<_>>());
+            assert!(m.iter().sum::<f32>().abs() < 1e-4);
+            assert!(m.iter().all(|v| v.abs() < 0.5));
+        }
+    }
+
+    #[test]
+    fn diffusion_weights() {
+        let sum = |dither| diffusion_kernel(dither).iter().map(|&(_, _, w)| w).sum::<f32>();
+        assert!((sum(Dither::FloydSteinberg) - 1.0).abs() < 1e-6);
+        assert!((sum(Dither::Sierra) - 1.0).abs() < 1e-6);
+        assert!((sum(Dither::Atkinson) - 6.0 / 8.0).abs() < 1e-6);
+
+        for dither in [Dither::FloydSteinberg, Dither::Atkinson, Dither::Sierra] {
+            assert!(dither.is_error_diffusion());
+            // Only cells that are matched later receive error.
+            assert!(diffusion_kernel(dither).iter().all(|&(dx, dy, _)| dy > 0 || (dy == 0 && dx > 0)));
+        }
+        for dither in [Dither::None, Dither::Bayer2, Dither::Bayer4, Dither::Bayer8] {
+            assert!(!dither.is_error_diffusion());
+        }
+    }
+
+    #[test]
+    fn diffuse_stays_on_the_grid() {
+        let mut grid = ErrorGrid::new(3, 2);
+        grid.diffuse(Dither::FloydSteinberg, 2, 0, [16.0; CHANNELS]);
+        assert_eq!(grid.get(1, 1), [3.0; CHANNELS]);
+        assert_eq!(grid.get(2, 1), [5.0; CHANNELS]);
+        assert_eq!(grid.get(0, 0), [0.0; CHANNELS]);
+        // Nothing is pushed past the last row.
+        grid.diffuse(Dither::FloydSteinberg, 0, 1, [16.0; CHANNELS]);
+        assert_eq!(grid.get(1, 1), [10.0; CHANNELS]);
+    }
+}
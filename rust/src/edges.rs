@@ -0,0 +1,150 @@
+use crate::kernel::{PackedTile, CHANNELS};
+
+// Edge orientation descriptors.
+//
+// Intensity matching can't tell `/` from `\` once both have about the same
+// amount of ink in a cell. Here each tile is reduced to a small histogram of
+// Sobel gradient orientations, split over a 2 x 2 grid so `_` and `-` (same
+// orientation, different position) stay apart, and tiles are compared by the
+// L1 distance between histograms.
+
+pub const ORIENTATION_BINS: usize = 8;
+pub const GRID: usize = 2;
+pub const DESCRIPTOR_LEN: usize = ORIENTATION_BINS * GRID * GRID;
+
+// Largest Sobel magnitude for luminance in 0..1.
+const MAX_MAGNITUDE: f32 = 4.0 * std::f32::consts::SQRT_2;
+
+pub type EdgeDescriptor = [f32; DESCRIPTOR_LEN];
+
+/// Luminance of each pixel in `0.0..=1.0`.
+pub fn luminance(tile: &PackedTile) -> Vec<f32> {
+    tile.data
+        .chunks_exact(CHANNELS)
+        .map(|p| (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.0)
+        .collect()
+}
+
+/// Sobel gradients (gx, gy) of a row-major luminance buffer, edges clamped.
+pub fn sobel(lum: &[f32], width: u32, height: u32) -> Vec<(f32, f32)> {
+    let (w, h) = (width as i64, height as i64);
+    let at = |x: i64, y: i64| lum[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize];
+
+    let mut gradients = Vec::with_capacity(lum.len());
+    for y in 0..h {
+        for x in 0..w {
+            let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1) - 2.0 * at(x - 1, y) - at(x - 1, y + 1);
+            let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1) - 2.0 * at(x, y - 1) - at(x + 1, y - 1);
+            gradients.push((gx, gy));
+        }
+    }
+
+    gradients
+}
+
+/// Magnitude weighted orientation histogram per grid cell. Orientations are
+/// taken modulo pi so dark-on-light and light-on-dark strokes agree, and the
+/// bins are scaled by pixel count so tiles of any size compare.
+pub fn edge_descriptor(tile: &PackedTile) -> EdgeDescriptor {
+    let mut descriptor = [0.0; DESCRIPTOR_LEN];
+    if tile.width == 0 || tile.height == 0 {
+        return descriptor;
+    }
+
+    let gradients = sobel(&luminance(tile), tile.width, tile.height);
+    let cell_pixels = (tile.width * tile.height) as f32 / (GRID * GRID) as f32;
+
+    for (i, &(gx, gy)) in gradients.iter().enumerate() {
+        let magnitude = (gx * gx + gy * gy).sqrt();
+        if magnitude <= f32::EPSILON {
+            continue;
+        }
+
+        // The edge runs perpendicular to the gradient.
+        let angle = (gy.atan2(gx) + std::f32::consts::FRAC_PI_2).rem_euclid(std::f32::consts::PI);
+        let bin = ((angle / std::f32::consts::PI) * ORIENTATION_BINS as f32) as usize % ORIENTATION_BINS;
+
+        let x = i as u32 % tile.width;
+        let y = i as u32 / tile.width;
+        let gx_cell = (x as usize * GRID) / tile.width as usize;
+        let gy_cell = (y as usize * GRID) / tile.height as usize;
+
+        descriptor[(gy_cell * GRID + gx_cell) * ORIENTATION_BINS + bin] +=
+            magnitude / (MAX_MAGNITUDE * cell_pixels);
+    }
+
+    descriptor
+}
+
+/// L1 distance between two descriptors, roughly in `0.0..=1.0`.
+pub fn edge_distance(a: &EdgeDescriptor, b: &EdgeDescriptor) -> f32 {
+    let s: f32 = a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum();
+    s / (GRID * GRID) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusttype::{point, Font, Scale};
+
+    const WIDTH: u32 = 16;
+    const HEIGHT: u32 = 32;
+
+    /// `c` drawn black on white in a `WIDTH` x `HEIGHT` cell.
+    fn tile(c: char) -> PackedTile {
+        let font = Font::try_from_bytes(include_bytes!("../fonts/Arial-Monospaced.ttf")).unwrap();
+        let scale = Scale::uniform(HEIGHT as f32);
+        let glyph = font.glyph(c).scaled(scale).positioned(point(0.0, font.v_metrics(scale).ascent));
+        let mut data = vec![255; (WIDTH * HEIGHT) as usize * CHANNELS];
+        let bb = glyph.pixel_bounding_box().unwrap();
+        glyph.draw(|x, y, v| {
+            let (x, y) = (x as i32 + bb.min.x, y as i32 + bb.min.y);
+            if (0..WIDTH as i32).contains(&x) && (0..HEIGHT as i32).contains(&y) {
+                let i = (y as u32 * WIDTH + x as u32) as usize * CHANNELS;
+                data[i..i + CHANNELS].fill(((1.0 - v) * 255.0).round() as u8);
+            }
+        });
+        PackedTile { width: WIDTH, height: HEIGHT, data }
+    }
+
+    /// The orientation bin with the most weight over the whole grid.
+    fn dominant_bin(descriptor: &EdgeDescriptor) -> usize {
+        let mut bins = [0.0; ORIENTATION_BINS];
+        for (i, v) in descriptor.iter().enumerate() {
+            bins[i % ORIENTATION_BINS] += v;
+        }
+        (0..ORIENTATION_BINS).max_by(|&a, &b| bins[a].total_cmp(&bins[b])).unwrap()
+    }
+
+    #[test]
+    fn strokes_have_distinct_descriptors() {
+        let chars = ['/', '\\', '|', '_', '-'];
+        let descriptors: Vec<EdgeDescriptor> = chars.iter().map(|&c| edge_descriptor(&tile(c))).collect();
+        for i in 0..chars.len() {
+            assert_eq!(edge_distance(&descriptors[i], &descriptors[i]), 0.0);
+            for j in i + 1..chars.len() {
+                let distance = edge_distance(&descriptors[i], &descriptors[j]);
+                assert!(distance > 0.05, "{} {} {}", chars[i], chars[j], distance);
+            }
+        }
+    }
+
+    #[test]
+    fn strokes_have_their_orientation() {
+        // Bins are 22.5 degrees of edge angle, clockwise from horizontal in
+        // image coordinates. Arial's slashes are steeper than 45 degrees.
+        let bin = |c| dominant_bin(&edge_descriptor(&tile(c)));
+        assert!([4, 5].contains(&bin('/')), "{}", bin('/'));
+        assert!([2, 3].contains(&bin('\\')), "{}", bin('\\'));
+        assert!([3, 4].contains(&bin('|')), "{}", bin('|'));
+        assert!([0, 7].contains(&bin('_')), "{}", bin('_'));
+        assert!([0, 7].contains(&bin('-')), "{}", bin('-'));
+
+        // `_` only has edges in the bottom half of the grid.
+        let underscore = edge_descriptor(&tile('_'));
+        assert!(underscore[..GRID * ORIENTATION_BINS].iter().all(|&v| v == 0.0));
+        assert!(underscore[GRID * ORIENTATION_BINS..].iter().sum::<f32>() > 0.0);
+    }
+}
@@ -0,0 +1,178 @@
+use rusttype::{Font, Scale};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::style::GlyphStyle;
+
+// Font fallback chain.
+//
+// A render draws from a primary font and any number of fallbacks. With a
+// `charset`, each character comes from the first font that maps it, so e.g.
+// ASCII can come from a monospaced font and block or Braille characters from
+// a symbol font. The cell is always the primary font's: fallbacks are scaled
+// so their ascent to descent spans the same height, and glyphs wider than the
+// cell are narrowed to fit. Style fonts (a bold or italic cut of the
+// primary font) are part of the stack too, but only ever supply their style.
+
+#[derive(Debug)]
+pub struct FontError {
+    /// Position of the font in the stack, 0 for the primary font.
+    pub index: usize,
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.index {
+            0 => write!(f, "Could not parse font."),
+            i => write!(f, "Could not parse font {} of the font stack.", i),
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+/// A font of the stack and a glyph id in it.
+pub type GlyphRef = (u8, u16);
+
+pub struct FontStack {
+    fonts: Vec<Font<'static>>,
+    data: Vec<Vec<u8>>,
+    /// `Regular` for the primary font and fallbacks.
+    styles: Vec<GlyphStyle>,
+}
+
+impl FontStack {
+    /// The primary font followed by its fallbacks, in order of preference.
+    pub fn new(data: Vec<Vec<u8>>) -> Result<FontStack, FontError> {
+        let fonts = data.iter()
+            .enumerate()
+            .map(|(index, bytes)| Font::try_from_vec(bytes.clone()).ok_or(FontError { index }))
+            .collect::<Result<Vec<_>, _>>()?;
+        if fonts.is_empty() {
+            return Err(FontError { index: 0 });
+        }
+        let styles = vec![GlyphStyle::Regular; fonts.len()];
+        Ok(FontStack { fonts, data, styles })
+    }
+
+    /// Adds the font that supplies `style`, replacing an earlier one.
+    pub fn add_style(&mut self, style: GlyphStyle, data: Vec<u8>) -> Result<(), FontError> {
+        let font = Font::try_from_vec(data.clone()).ok_or(FontError { index: self.fonts.len() })?;
+        if let Some(i) = self.style_font(style) {
+            self.fonts[i as usize] = font;
+            self.data[i as usize] = data;
+            self.styles[i as usize] = style;
+            return Ok(());
+        }
+        self.fonts.push(font);
+        self.data.push(data);
+        self.styles.push(style);
+        Ok(())
+    }
+
+    /// Position of the font that supplies `style`, if one was added.
+    pub fn style_font(&self, style: GlyphStyle) -> Option<u8> {
+        match style {
+            GlyphStyle::Regular => None,
+            style => self.styles.iter().position(|&s| s == style).map(|i| i as u8),
+        }
+    }
+
+    pub fn primary(&self) -> &Font<'static> {
+        &self.fonts[0]
+    }
+
+    pub fn fonts(&self) -> &[Font<'static>] {
+        &self.fonts
+    }
+
+    /// The font files, primary first.
+    pub fn data(&self) -> &[Vec<u8>] {
+        &self.data
+    }
+
+    /// The style each font supplies.
+    pub fn styles(&self) -> &[GlyphStyle] {
+        &self.styles
+    }
+
+    /// The first regular font that maps `c`, and the glyph id there.
+    pub fn resolve(&self, c: char) -> Option<(u8, u16)> {
+        self.fonts.iter()
+            .enumerate()
+            .filter(|&(i, _)| self.styles[i] == GlyphStyle::Regular)
+            .map(|(i, font)| (i as u8, font.glyph(c).id().0))
+            .find(|&(_, id)| id != 0)
+    }
+
+    /// The characters of `charset` and their glyphs, leaving out repeats of
+    /// a glyph, followed by the characters no font has.
+    pub fn resolve_charset(&self, charset: &str) -> (Vec<(char, GlyphRef)>, Vec<char>) {
+        let (mut resolved, mut missing) = (Vec::new(), Vec::new());
+        for c in charset.chars() {
+            match self.resolve(c) {
+                Some(glyph) if !resolved.iter().any(|&(_, g)| g == glyph) => resolved.push((c, glyph)),
+                Some(_) => {}
+                None => missing.push(c),
+            }
+        }
+        (resolved, missing)
+    }
+
+    /// The size to draw each font at so it fills the primary font's line.
+    pub fn scales(&self, scale: Scale) -> Vec<Scale> {
+        let height = |font: &Font| {
+            let v_metrics = font.v_metrics(scale);
+            v_metrics.ascent - v_metrics.descent
+        };
+        let primary = height(self.primary());
+
+        self.fonts.iter()
+            .enumerate()
+            .map(|(i, font)| match height(font) {
+                h if i == 0 || h <= 0.0 => scale,
+                h => Scale { x: scale.x * primary / h, y: scale.y * primary / h },
+            })
+            .collect()
+    }
+
+    /// [`glyph_codepoints`] of every font.
+    pub fn codepoints(&self) -> Vec<HashMap<u16, char>> {
+        self.data.iter().map(|data| glyph_codepoints(data)).collect()
+    }
+}
+
+/// The lowest codepoint mapping to each glyph id.
+pub fn glyph_codepoints(font_data: &[u8]) -> HashMap<u16, char> {
+    let mut map = HashMap::new();
+    let Ok(face) = ttf_parser::Face::parse(font_data, 0) else {
+        return map;
+    };
+    let Some(cmap) = face.tables().cmap else {
+        return map;
+    };
+
+    for subtable in cmap.subtables.into_iter().filter(|s| s.is_unicode()) {
+        subtable.codepoints(|cp| {
+            if let (Some(glyph), Some(c)) = (subtable.glyph_index(cp), char::from_u32(cp)) {
+                let entry = map.entry(glyph.0).or_insert(c);
+                *entry = (*entry).min(c);
+            }
+        });
+    }
+    map
+}
+
+/// `U+0041 A`, or `#<id>` for glyphs no codepoint maps to. Glyphs from a
+/// fallback font are suffixed with its position in the stack.
+pub fn glyph_label(font: u8, glyph: u16, codepoints: &[HashMap<u16, char>]) -> String {
+    let label = match codepoints.get(font as usize).and_then(|map| map.get(&glyph)) {
+        Some(&c) if !c.is_control() && !c.is_whitespace() => format!("U+{:04X} {}", c as u32, c),
+        Some(&c) => format!("U+{:04X}", c as u32),
+        None => format!("#{}", glyph),
+    };
+    match font {
+        0 => label,
+        font => format!("{} [{}]", label, font),
+    }
+}
@@ -0,0 +1,214 @@
+use image::Rgba;
+use std::f32::consts::PI;
+use wasm_bindgen::prelude::*;
+
+use crate::colour;
+
+// Gradient palettes.
+//
+// `gradient` returns exactly `count` colours evenly spaced along a piecewise
+// linear path through the stops, first and last stop included. Which space
+// the interpolation happens in changes the midpoints: sRGB is what a naive
+// lerp of the bytes gives, linear RGB mixes like light, Lab and OKLCH keep
+// the steps perceptually even (OKLCH also walks the hue wheel instead of
+// cutting through grey).
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradientSpace {
+    Srgb,
+    LinearRgb,
+    Lab,
+    Oklch,
+}
+
+impl GradientSpace {
+    pub fn from_name(name: &str) -> Option<GradientSpace> {
+        match name {
+            "srgb" => Some(GradientSpace::Srgb),
+            "linear" => Some(GradientSpace::LinearRgb),
+            "lab" => Some(GradientSpace::Lab),
+            "oklch" => Some(GradientSpace::Oklch),
+            _ => None,
+        }
+    }
+
+    fn to_space(self, [r, g, b, _]: [u8; 4]) -> [f32; 3] {
+        let rgb = [r, g, b];
+        match self {
+            GradientSpace::Srgb => rgb.map(|v| v as f32 / 255.0),
+            GradientSpace::LinearRgb => colour::rgb8_to_linear(rgb),
+            GradientSpace::Lab => colour::rgb8_to_lab(rgb),
+            GradientSpace::Oklch => colour::oklab_to_oklch(colour::linear_to_oklab(colour::rgb8_to_linear(rgb))),
+        }
+    }
+
+    fn to_rgb8(self, v: [f32; 3]) -> [u8; 3] {
+        match self {
+            GradientSpace::Srgb => v.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8),
+            GradientSpace::LinearRgb => colour::linear_to_rgb8(v),
+            GradientSpace::Lab => colour::lab_to_rgb8(v),
+            GradientSpace::Oklch => colour::linear_to_rgb8(colour::oklab_to_linear(colour::oklch_to_oklab(v))),
+        }
+    }
+
+    fn mix(self, a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+        let lerp = |x: f32, y: f32| x + (y - x) * t;
+        if self != GradientSpace::Oklch {
+            return [0, 1, 2].map(|i| lerp(a[i], b[i]));
+        }
+
+        // Greys have no hue, so take the other end's instead of swinging
+        // through red.
+        const ACHROMATIC: f32 = 1e-4;
+        let (mut ha, mut hb) = (a[2], b[2]);
+        if a[1] < ACHROMATIC {
+            ha = hb;
+        }
+        if b[1] < ACHROMATIC {
+            hb = ha;
+        }
+
+        // Shorter way round the wheel.
+        let mut dh = hb - ha;
+        if dh > PI {
+            dh -= 2.0 * PI;
+        } else if dh < -PI {
+            dh += 2.0 * PI;
+        }
+
+        [lerp(a[0], b[0]), lerp(a[1], b[1]), ha + dh * t]
+    }
+}
+
+/// `count` colours evenly spaced through `stops`. A single colour is the
+/// first stop.
+pub fn gradient(stops: &[Rgba<u8>], count: usize, space: GradientSpace) -> Vec<Rgba<u8>> {
+    if stops.is_empty() || count == 0 {
+        return Vec::new();
+    }
+    if stops.len() == 1 || count == 1 {
+        return vec![stops[0]; count];
+    }
+
+    let points: Vec<[f32; 3]> = stops.iter().map(|s| space.to_space(s.0)).collect();
+    let segments = stops.len() - 1;
+
+    (0..count)
+        .map(|i| {
+            // Position along the whole path, split into segment and offset.
+            let position = i as f32 * segments as f32 / (count - 1) as f32;
+            let segment = (position as usize).min(segments - 1);
+            let t = position - segment as f32;
+
+            let (a, b) = (stops[segment].0, stops[segment + 1].0);
+            let [r, g, bl] = match t {
+                t if t <= 0.0 => [a[0], a[1], a[2]],
+                t if t >= 1.0 => [b[0], b[1], b[2]],
+                t => space.to_rgb8(space.mix(points[segment], points[segment + 1], t)),
+            };
+            let alpha = (a[3] as f32 + (b[3] as f32 - a[3] as f32) * t).round() as u8;
+
+            Rgba([r, g, bl, alpha])
+        })
+        .collect()
+}
+
+/// `count` colours through `stops` (packed RGBA) in `space` (`srgb`,
+/// `linear`, `lab` or `oklch`), as packed RGBA bytes.
+#[wasm_bindgen]
+pub fn gradient_palette(stops: &[u8], count: u32, space: &str) -> Result<Vec<u8>, JsValue> {
+    let space = GradientSpace::from_name(space)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown gradient space: {}", space)))?;
+    let stops: Vec<Rgba<u8>> = stops.chunks_exact(4).map(|c| Rgba([c[0], c[1], c[2], c[3]])).collect();
+
+    Ok(gradient(&stops, count as usize, space).into_iter().flat_map(|c| c.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    const SPACES: [GradientSpace; 4] = [
+        GradientSpace::Srgb,
+        GradientSpace::LinearRgb,
+        GradientSpace::Lab,
+        GradientSpace::Oklch,
+    ];
+
+    #[test]
+    fn exact_count_and_endpoints() {
+        for space in SPACES {
+            for count in 2..=300 {
+                let colours = gradient(&[RED, WHITE, BLUE], count, space);
+                assert_eq!(colours.len(), count, "{:?}", space);
+                assert_eq!(colours[0], RED);
+                assert_eq!(colours[count - 1], BLUE);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_counts() {
+        assert!(gradient(&[BLACK, WHITE], 0, GradientSpace::Srgb).is_empty());
+        assert_eq!(gradient(&[BLACK, WHITE], 1, GradientSpace::Srgb), vec![BLACK]);
+        assert_eq!(gradient(&[RED], 3, GradientSpace::Lab), vec![RED; 3]);
+        assert!(gradient(&[], 3, GradientSpace::Lab).is_empty());
+    }
+
+    #[test]
+    fn middle_stops_are_hit() {
+        for space in SPACES {
+            assert_eq!(gradient(&[RED, WHITE, BLUE], 5, space)[2], WHITE, "{:?}", space);
+        }
+    }
+
+    #[test]
+    fn srgb_is_even_in_bytes() {
+        let grey: Vec<u8> = gradient(&[BLACK, WHITE], 4, GradientSpace::Srgb).iter().map(|c| c[0]).collect();
+        assert_eq!(grey, vec![0, 85, 170, 255]);
+    }
+
+    #[test]
+    fn linear_midpoint_is_brighter() {
+        let srgb = gradient(&[BLACK, WHITE], 3, GradientSpace::Srgb)[1];
+        let linear = gradient(&[BLACK, WHITE], 3, GradientSpace::LinearRgb)[1];
+        assert_eq!(srgb[0], 128);
+        assert_eq!(linear[0], 188);
+    }
+
+    #[test]
+    fn greys_stay_grey() {
+        for space in SPACES {
+            for c in gradient(&[BLACK, WHITE], 9, space) {
+                assert!(c[0].abs_diff(c[1]) <= 1 && c[1].abs_diff(c[2]) <= 1, "{:?} {:?}", space, c);
+            }
+        }
+    }
+
+    #[test]
+    fn oklch_keeps_chroma() {
+        // Red to blue through OKLCH goes round via magenta instead of
+        // through the dull purple a straight RGB mix gives.
+        let middle = gradient(&[RED, BLUE], 3, GradientSpace::Oklch)[1];
+        let straight = gradient(&[RED, BLUE], 3, GradientSpace::Srgb)[1];
+        let chroma = |c: Rgba<u8>| c.0[..3].iter().max().unwrap() - c.0[..3].iter().min().unwrap();
+        assert!(chroma(middle) > chroma(straight), "{:?} {:?}", middle, straight);
+    }
+
+    #[test]
+    fn alpha_is_interpolated() {
+        let colours = gradient(&[Rgba([0, 0, 0, 0]), BLACK], 3, GradientSpace::Lab);
+        assert_eq!(colours.iter().map(|c| c[3]).collect::<Vec<_>>(), vec![0, 128, 255]);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(GradientSpace::from_name("oklch"), Some(GradientSpace::Oklch));
+        assert_eq!(GradientSpace::from_name("hsv"), None);
+    }
+}
@@ -0,0 +1,197 @@
+use js_sys::Uint8Array;
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+// Bucketed colour histogram, the Rust side of `public/HistogramWorker.js`.
+//
+// Same semantics as the worker: exact colours are counted, then taken from
+// most to least frequent and each one absorbs every remaining colour closer
+// than `bucket_size` (euclidean RGB). Instead of rescanning a sorted list the
+// candidates live in a grid of `bucket_size` cells, so each absorption only
+// looks at the neighbouring cells.
+
+#[wasm_bindgen]
+pub struct ColourHistogram {
+    colours: Vec<u8>,
+    counts: Vec<u32>,
+}
+
+#[wasm_bindgen]
+impl ColourHistogram {
+    /// Bucket colours as RGB triples, most frequent first.
+    #[wasm_bindgen(getter)]
+    pub fn colours(&self) -> Vec<u8> {
+        self.colours.clone()
+    }
+
+    /// Pixel count of each bucket.
+    #[wasm_bindgen(getter)]
+    pub fn counts(&self) -> Vec<u32> {
+        self.counts.clone()
+    }
+}
+
+/// Histogram of raw RGBA pixel data (e.g. canvas `ImageData.data`).
+pub fn histogram_rgba(data: &[u8], bucket_size: f32) -> Vec<([u8; 3], u32)> {
+    // Count plus first occurrence, ties keep first-seen order like the
+    // worker's stable sort over object keys.
+    let mut exact: HashMap<[u8; 3], (u32, usize)> = HashMap::new();
+    for (i, p) in data.chunks_exact(4).enumerate() {
+        exact.entry([p[0], p[1], p[2]]).or_insert((0, i)).0 += 1;
+    }
+
+    let mut ordered: Vec<([u8; 3], (u32, usize))> = exact.into_iter().collect();
+    ordered.sort_by_key(|&(_, (count, first))| (std::cmp::Reverse(count), first));
+    let sorted: Vec<([u8; 3], u32)> = ordered.into_iter().map(|(colour, (count, _))| (colour, count)).collect();
+
+    if bucket_size <= 0.0 {
+        return sorted;
+    }
+
+    let cell = |c: [u8; 3]| c.map(|v| (v as f32 / bucket_size) as i32);
+    let mut grid: HashMap<[i32; 3], Vec<usize>> = HashMap::new();
+    for (i, &(colour, _)) in sorted.iter().enumerate() {
+        grid.entry(cell(colour)).or_default().push(i);
+    }
+
+    let mut absorbed = vec![false; sorted.len()];
+    let mut buckets = Vec::new();
+    for i in 0..sorted.len() {
+        if absorbed[i] {
+            continue;
+        }
+        absorbed[i] = true;
+
+        let (colour, mut count) = sorted[i];
+        let [cx, cy, cz] = cell(colour);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let Some(members) = grid.get_mut(&[cx + dx, cy + dy, cz + dz]) else {
+                        continue;
+                    };
+
+                    members.retain(|&j| {
+                        if absorbed[j] {
+                            return false;
+                        }
+                        if distance(colour, sorted[j].0) < bucket_size {
+                            absorbed[j] = true;
+                            count += sorted[j].1;
+                            return false;
+                        }
+                        true
+                    });
+                }
+            }
+        }
+
+        buckets.push((colour, count));
+    }
+
+    buckets
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> f32 {
+    (0..3).map(|c| (a[c] as f32 - b[c] as f32).powi(2)).sum::<f32>().sqrt()
+}
+
+fn to_js(buckets: Vec<([u8; 3], u32)>) -> ColourHistogram {
+    ColourHistogram {
+        colours: buckets.iter().flat_map(|(c, _)| *c).collect(),
+        counts: buckets.iter().map(|(_, n)| *n).collect(),
+    }
+}
+
+/// Histogram of an encoded image (PNG, JPEG, ...).
+#[wasm_bindgen]
+pub fn colour_histogram(image_bytes: Uint8Array, bucket_size: f32) -> Result<ColourHistogram, JsValue> {
+    let img = image::load_from_memory(&image_bytes.to_vec())
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    Ok(to_js(histogram_rgba(img.to_rgba8().as_raw(), bucket_size)))
+}
+
+/// Histogram of raw RGBA pixel data, as the histogram worker receives it.
+#[wasm_bindgen]
+pub fn colour_histogram_rgba(data: &[u8], bucket_size: f32) -> ColourHistogram {
+    to_js(histogram_rgba(data, bucket_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `generateHistogram` from the worker, line for line.
+    fn worker_histogram(data: &[u8], bucket_size: f32) -> Vec<([u8; 3], u32)> {
+        let mut histogram: Vec<([u8; 3], u32)> = Vec::new();
+        for p in data.chunks_exact(4) {
+            let key = [p[0], p[1], p[2]];
+            match histogram.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, count)) => *count += 1,
+                None => histogram.push((key, 1)),
+            }
+        }
+
+        // A stable sort, like `Array.prototype.sort`.
+        let mut sorted_keys = histogram.clone();
+        sorted_keys.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+        let mut bucketed = Vec::new();
+        while !sorted_keys.is_empty() {
+            let (current, mut count) = sorted_keys.remove(0);
+            let mut i = 0;
+            while i < sorted_keys.len() {
+                if distance(current, sorted_keys[i].0) < bucket_size {
+                    count += sorted_keys.remove(i).1;
+                } else {
+                    i += 1;
+                }
+            }
+            bucketed.push((current, count));
+        }
+        bucketed
+    }
+
+    /// Pixels clustered around a few colours, from a fixed LCG.
+    fn pixels() -> Vec<u8> {
+        let mut state = 12345u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 16) as u8
+        };
+        let centres = [[30, 30, 30], [200, 40, 40], [40, 200, 90], [250, 250, 250]];
+        (0..3000)
+            .flat_map(|_| {
+                let centre = centres[next() as usize % centres.len()];
+                let spread = next() % 48;
+                let [r, g, b] = centre.map(|v: u8| v.saturating_add(next() % (spread + 1)).saturating_sub(spread / 2));
+                [r, g, b, 255]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_the_worker() {
+        let data = pixels();
+        for bucket_size in [0.0, 1.0, 2.5, 10.0, 24.0, 60.0, 200.0, 500.0] {
+            assert_eq!(histogram_rgba(&data, bucket_size), worker_histogram(&data, bucket_size), "{}", bucket_size);
+        }
+    }
+
+    #[test]
+    fn bucket_boundaries() {
+        // 10 apart: distances must be strictly below the bucket size.
+        let data = [[0, 0, 0], [0, 0, 0], [10, 0, 0], [0, 6, 8], [20, 0, 0]]
+            .iter()
+            .flat_map(|&[r, g, b]| [r, g, b, 255])
+            .collect::<Vec<u8>>();
+        let exact = [([0, 0, 0], 2), ([10, 0, 0], 1), ([0, 6, 8], 1), ([20, 0, 0], 1)];
+        assert_eq!(histogram_rgba(&data, 10.0), exact);
+        assert_eq!(histogram_rgba(&data, 10.5), [([0, 0, 0], 4), ([20, 0, 0], 1)]);
+        // Exact counts, ties in first-seen order.
+        assert_eq!(histogram_rgba(&data, 0.0), exact);
+        // Alpha is ignored.
+        assert_eq!(histogram_rgba(&[1, 2, 3, 0, 1, 2, 3, 255], 0.0), [([1, 2, 3], 2)]);
+    }
+}
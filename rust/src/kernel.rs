@@ -0,0 +1,451 @@
+use image::{GenericImageView, Rgba, RgbaImage};
+
+use crate::colour::ColourSpace;
+
+// Tile/glyph distance kernel.
+//
+// Tiles and glyph renders are copied once into contiguous, row-major RGB
+// buffers so the hot loop is a straight sum of absolute differences over two
+// slices instead of a `get_pixel` call per pixel and channel. The inner loop
+// uses wasm `simd128` when the target is built with it, SSE2 on x86_64 and a
+// chunked loop the compiler auto-vectorises everywhere else.
+
+pub const CHANNELS: usize = 3;
+
+/// An image region packed as `width * height * 3` RGB bytes (alpha dropped).
+#[derive(Clone, Debug)]
+pub struct PackedTile {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl PackedTile {
+    /// Copies the `width` x `height` region at (`x`, `y`) out of `img`.
+    pub fn from_view<I>(img: &I, x: u32, y: u32, width: u32, height: u32) -> PackedTile
+    where
+        I: GenericImageView<Pixel = Rgba<u8>>,
+    {
+        let mut data = Vec::with_capacity((width * height) as usize * CHANNELS);
+        for py in y..y + height {
+            for px in x..x + width {
+                let p = img.get_pixel(px, py).0;
+                data.extend_from_slice(&p[..CHANNELS]);
+            }
+        }
+
+        PackedTile { width, height, data }
+    }
+
+    /// Like [`PackedTile::from_view`] but the region may extend past the
+    /// image, in which case the nearest edge pixel is repeated.
+    pub fn from_view_clamped<I>(img: &I, x: i64, y: i64, width: u32, height: u32) -> PackedTile
+    where
+        I: GenericImageView<Pixel = Rgba<u8>>,
+    {
+        let (iw, ih) = img.dimensions();
+        let mut data = Vec::with_capacity((width * height) as usize * CHANNELS);
+        for py in y..y + height as i64 {
+            let py = py.clamp(0, ih as i64 - 1) as u32;
+            for px in x..x + width as i64 {
+                let px = px.clamp(0, iw as i64 - 1) as u32;
+                let p = img.get_pixel(px, py).0;
+                data.extend_from_slice(&p[..CHANNELS]);
+            }
+        }
+
+        PackedTile { width, height, data }
+    }
+
+    pub fn from_rgba(img: &RgbaImage) -> PackedTile {
+        let data = img
+            .pixels()
+            .flat_map(|p| [p.0[0], p.0[1], p.0[2]])
+            .collect();
+
+        PackedTile { width: img.width(), height: img.height(), data }
+    }
+
+    /// Converts the samples to `f32` in `0.0..=255.0`.
+    pub fn to_f32(&self) -> PackedTileF32 {
+        PackedTileF32 {
+            width: self.width,
+            height: self.height,
+            data: self.data.iter().map(|&v| v as f32).collect(),
+        }
+    }
+
+    /// Converts every pixel to `space` (see [`ColourSpace::encode`]).
+    pub fn to_colour_space(&self, space: ColourSpace) -> PackedTileF32 {
+        if space == ColourSpace::Srgb {
+            return self.to_f32();
+        }
+
+        PackedTileF32 {
+            width: self.width,
+            height: self.height,
+            data: self.data.chunks_exact(CHANNELS).flat_map(|p| space.encode([p[0], p[1], p[2]])).collect(),
+        }
+    }
+
+    fn row(&self, y: u32, width: u32) -> &[u8] {
+        let start = (y * self.width) as usize * CHANNELS;
+        &self.data[start..start + width as usize * CHANNELS]
+    }
+}
+
+/// Same layout as [`PackedTile`] with `f32` samples, for scoring in
+/// non-byte colour spaces.
+#[derive(Clone, Debug)]
+pub struct PackedTileF32 {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<f32>,
+}
+
+impl PackedTileF32 {
+    fn row(&self, y: u32, width: u32) -> &[f32] {
+        let start = (y * self.width) as usize * CHANNELS;
+        &self.data[start..start + width as usize * CHANNELS]
+    }
+}
+
+/// Sum of absolute differences over the overlapping region of two tiles.
+/// Returns the distance and the number of pixels compared.
+pub fn tile_distance(a: &PackedTile, b: &PackedTile) -> (u64, u32) {
+    let width = std::cmp::min(a.width, b.width);
+    let height = std::cmp::min(a.height, b.height);
+
+    if a.width == b.width {
+        let len = (width * height) as usize * CHANNELS;
+        return (sad_u8(&a.data[..len], &b.data[..len]), width * height);
+    }
+
+    let mut s = 0;
+    for y in 0..height {
+        s += sad_u8(a.row(y, width), b.row(y, width));
+    }
+
+    (s, width * height)
+}
+
+/// Weighted sum of absolute differences of two equally sized tiles, with one
+/// weight per sample (three per pixel).
+pub fn tile_distance_weighted(a: &PackedTile, b: &PackedTile, weights: &[f32]) -> f32 {
+    assert_eq!((a.width, a.height), (b.width, b.height));
+    weighted_sad_u8(&a.data, &b.data, weights)
+}
+
+/// [`tile_distance`] for `f32` tiles.
+pub fn tile_distance_f32(a: &PackedTileF32, b: &PackedTileF32) -> (f32, u32) {
+    let width = std::cmp::min(a.width, b.width);
+    let height = std::cmp::min(a.height, b.height);
+
+    if a.width == b.width {
+        let len = (width * height) as usize * CHANNELS;
+        return (sad_f32(&a.data[..len], &b.data[..len]), width * height);
+    }
+
+    let mut s = 0.0;
+    for y in 0..height {
+        s += sad_f32(a.row(y, width), b.row(y, width));
+    }
+
+    (s, width * height)
+}
+
+/// [`tile_distance_weighted`] for `f32` tiles.
+pub fn tile_distance_weighted_f32(a: &PackedTileF32, b: &PackedTileF32, weights: &[f32]) -> f32 {
+    assert_eq!((a.width, a.height), (b.width, b.height));
+    weighted_sad_f32(&a.data, &b.data, weights)
+}
+
+/// Sum of absolute differences of two equally sized byte slices.
+pub fn sad_u8(a: &[u8], b: &[u8]) -> u64 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+    {
+        sad_u8_simd128(a, b)
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    {
+        sad_u8_sse2(a, b)
+    }
+
+    #[cfg(not(any(
+        all(target_arch = "wasm32", target_feature = "simd128"),
+        all(target_arch = "x86_64", target_feature = "sse2")
+    )))]
+    {
+        sad_u8_chunked(a, b)
+    }
+}
+
+/// Sum of absolute differences of two equally sized `f32` slices.
+pub fn sad_f32(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+    {
+        sad_f32_simd128(a, b)
+    }
+
+    #[cfg(not(all(target_arch = "wasm32", target_feature = "simd128")))]
+    {
+        sad_f32_chunked(a, b)
+    }
+}
+
+/// Sum of `weights[i] * |a[i] - b[i]|`.
+pub fn weighted_sad_u8(a: &[u8], b: &[u8], weights: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    assert_eq!(a.len(), weights.len());
+
+    let mut acc = [0f32; 8];
+    let mut ca = a.chunks_exact(8);
+    let mut cb = b.chunks_exact(8);
+    let mut cw = weights.chunks_exact(8);
+    for ((x, y), w) in (&mut ca).zip(&mut cb).zip(&mut cw) {
+        for i in 0..8 {
+            acc[i] += w[i] * x[i].abs_diff(y[i]) as f32;
+        }
+    }
+
+    let tail: f32 = ca.remainder().iter()
+        .zip(cb.remainder())
+        .zip(cw.remainder())
+        .map(|((x, y), w)| w * x.abs_diff(*y) as f32)
+        .sum();
+    acc.iter().sum::<f32>() + tail
+}
+
+/// Sum of `weights[i] * |a[i] - b[i]|` for `f32` samples.
+pub fn weighted_sad_f32(a: &[f32], b: &[f32], weights: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    assert_eq!(a.len(), weights.len());
+
+    let mut acc = [0f32; 8];
+    let mut ca = a.chunks_exact(8);
+    let mut cb = b.chunks_exact(8);
+    let mut cw = weights.chunks_exact(8);
+    for ((x, y), w) in (&mut ca).zip(&mut cb).zip(&mut cw) {
+        for i in 0..8 {
+            acc[i] += w[i] * (x[i] - y[i]).abs();
+        }
+    }
+
+    let tail: f32 = ca.remainder().iter()
+        .zip(cb.remainder())
+        .zip(cw.remainder())
+        .map(|((x, y), w)| w * (x - y).abs())
+        .sum();
+    acc.iter().sum::<f32>() + tail
+}
+
+/// Portable fallback for targets without a SIMD kernel below.
+#[cfg(any(
+    test,
+    not(any(
+        all(target_arch = "wasm32", target_feature = "simd128"),
+        all(target_arch = "x86_64", target_feature = "sse2")
+    ))
+))]
+fn sad_u8_chunked(a: &[u8], b: &[u8]) -> u64 {
+    const LANES: usize = 16;
+    let mut acc = [0u32; LANES];
+    let mut total = 0u64;
+
+    // The u32 lanes can hold 2^24 chunks of 255 before overflowing; flush
+    // well before that.
+    for (blocks_a, blocks_b) in a.chunks(LANES * 65536).zip(b.chunks(LANES * 65536)) {
+        let mut ca = blocks_a.chunks_exact(LANES);
+        let mut cb = blocks_b.chunks_exact(LANES);
+        for (x, y) in (&mut ca).zip(&mut cb) {
+            for i in 0..LANES {
+                acc[i] += x[i].abs_diff(y[i]) as u32;
+            }
+        }
+        for (x, y) in ca.remainder().iter().zip(cb.remainder()) {
+            total += x.abs_diff(*y) as u64;
+        }
+        total += acc.iter().map(|&v| v as u64).sum::<u64>();
+        acc = [0; LANES];
+    }
+
+    total
+}
+
+/// Portable fallback for targets without the SIMD kernel below.
+#[cfg(any(test, not(all(target_arch = "wasm32", target_feature = "simd128"))))]
+fn sad_f32_chunked(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0f32; 8];
+    let mut ca = a.chunks_exact(8);
+    let mut cb = b.chunks_exact(8);
+    for (x, y) in (&mut ca).zip(&mut cb) {
+        for i in 0..8 {
+            acc[i] += (x[i] - y[i]).abs();
+        }
+    }
+
+    let tail: f32 = ca.remainder().iter().zip(cb.remainder()).map(|(x, y)| (x - y).abs()).sum();
+    acc.iter().sum::<f32>() + tail
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+fn sad_u8_sse2(a: &[u8], b: &[u8]) -> u64 {
+    use std::arch::x86_64::*;
+
+    let mut ca = a.chunks_exact(16);
+    let mut cb = b.chunks_exact(16);
+    let mut total = 0u64;
+
+    // SAFETY: sse2 is statically enabled and every load reads exactly the 16
+    // bytes of a `chunks_exact(16)` chunk.
+    unsafe {
+        let mut acc = _mm_setzero_si128();
+        for (x, y) in (&mut ca).zip(&mut cb) {
+            let vx = _mm_loadu_si128(x.as_ptr() as *const __m128i);
+            let vy = _mm_loadu_si128(y.as_ptr() as *const __m128i);
+            acc = _mm_add_epi64(acc, _mm_sad_epu8(vx, vy));
+        }
+        let mut lanes = [0u64; 2];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, acc);
+        total += lanes[0] + lanes[1];
+    }
+
+    for (x, y) in ca.remainder().iter().zip(cb.remainder()) {
+        total += x.abs_diff(*y) as u64;
+    }
+
+    total
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+fn sad_u8_simd128(a: &[u8], b: &[u8]) -> u64 {
+    use std::arch::wasm32::*;
+
+    let mut ca = a.chunks_exact(16);
+    let mut cb = b.chunks_exact(16);
+    let mut total = 0u64;
+
+    // SAFETY: every load reads exactly the 16 bytes of a `chunks_exact(16)`
+    // chunk.
+    unsafe {
+        let mut acc = u32x4_splat(0);
+        for (x, y) in (&mut ca).zip(&mut cb) {
+            let vx = v128_load(x.as_ptr() as *const v128);
+            let vy = v128_load(y.as_ptr() as *const v128);
+            let diff = v128_or(u8x16_sub_sat(vx, vy), u8x16_sub_sat(vy, vx));
+            let pairs = u16x8_extadd_pairwise_u8x16(diff);
+            acc = i32x4_add(acc, u32x4_extadd_pairwise_u16x8(pairs));
+        }
+        total += u32x4_extract_lane::<0>(acc) as u64
+            + u32x4_extract_lane::<1>(acc) as u64
+            + u32x4_extract_lane::<2>(acc) as u64
+            + u32x4_extract_lane::<3>(acc) as u64;
+    }
+
+    for (x, y) in ca.remainder().iter().zip(cb.remainder()) {
+        total += x.abs_diff(*y) as u64;
+    }
+
+    total
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+fn sad_f32_simd128(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::wasm32::*;
+
+    let mut ca = a.chunks_exact(4);
+    let mut cb = b.chunks_exact(4);
+    let mut total;
+
+    // SAFETY: every load reads exactly the 4 floats of a `chunks_exact(4)`
+    // chunk.
+    unsafe {
+        let mut acc = f32x4_splat(0.0);
+        for (x, y) in (&mut ca).zip(&mut cb) {
+            let vx = v128_load(x.as_ptr() as *const v128);
+            let vy = v128_load(y.as_ptr() as *const v128);
+            acc = f32x4_add(acc, f32x4_abs(f32x4_sub(vx, vy)));
+        }
+        total = f32x4_extract_lane::<0>(acc)
+            + f32x4_extract_lane::<1>(acc)
+            + f32x4_extract_lane::<2>(acc)
+            + f32x4_extract_lane::<3>(acc);
+    }
+
+    for (x, y) in ca.remainder().iter().zip(cb.remainder()) {
+        total += (x - y).abs();
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64, enough for test data without a rand dependency.
+    fn random_bytes(n: usize, mut seed: u64) -> Vec<u8> {
+        (0..n)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    fn sad_scalar(a: &[u8], b: &[u8]) -> u64 {
+        a.iter().zip(b).map(|(x, y)| x.abs_diff(*y) as u64).sum()
+    }
+
+    /// Lengths around the 16 byte lane width, at offsets that misalign the
+    /// slices.
+    fn cases() -> Vec<(usize, usize)> {
+        let mut cases = Vec::new();
+        for len in (0..70).chain([255, 1000, 4099]) {
+            for offset in [0, 1, 3, 7, 15] {
+                cases.push((len, offset));
+            }
+        }
+        cases
+    }
+
+    #[test]
+    fn u8_kernels_match_scalar() {
+        let a = random_bytes(4200, 0x9E37_79B9_7F4A_7C15);
+        let b = random_bytes(4200, 0xD1B5_4A32_D192_ED03);
+
+        for (len, offset) in cases() {
+            let (x, y) = (&a[offset..offset + len], &b[offset + 1..offset + 1 + len]);
+            let expected = sad_scalar(x, y);
+            assert_eq!(sad_u8(x, y), expected, "len {} offset {}", len, offset);
+            assert_eq!(sad_u8_chunked(x, y), expected, "len {} offset {}", len, offset);
+            #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+            assert_eq!(sad_u8_sse2(x, y), expected, "len {} offset {}", len, offset);
+            #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+            assert_eq!(sad_u8_simd128(x, y), expected, "len {} offset {}", len, offset);
+        }
+    }
+
+    #[test]
+    fn f32_kernels_match_scalar() {
+        let a: Vec<f32> = random_bytes(4200, 7).into_iter().map(|v| v as f32 / 7.0).collect();
+        let b: Vec<f32> = random_bytes(4200, 11).into_iter().map(|v| v as f32 / 7.0).collect();
+
+        for (len, offset) in cases() {
+            let (x, y) = (&a[offset..offset + len], &b[offset + 1..offset + 1 + len]);
+            let expected: f64 = x.iter().zip(y).map(|(p, q)| (p - q).abs() as f64).sum();
+            let tolerance = 1e-5 * expected.max(1.0);
+            assert!((sad_f32(x, y) as f64 - expected).abs() <= tolerance, "len {} offset {}", len, offset);
+            assert!((sad_f32_chunked(x, y) as f64 - expected).abs() <= tolerance, "len {} offset {}", len, offset);
+            #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+            assert!((sad_f32_simd128(x, y) as f64 - expected).abs() <= tolerance, "len {} offset {}", len, offset);
+        }
+    }
+}
@@ -1,7 +1,9 @@
+// The crate name is part of the wasm-pack output the web app imports.
+#![allow(non_snake_case)]
+
 use wasm_bindgen::prelude::*;
 use js_sys::Uint8Array;
 
-use ttf_parser;
 use serde::{Deserialize, Serialize};
 use serde_wasm_bindgen::to_value;
 
@@ -30,9 +32,30 @@ struct FontInfo {
     is_variable: String
 }
 
-fn load_font_info(font_data: &Vec<u8>) -> FontInfo {
-
-    let face = match ttf_parser::Face::parse(&font_data, 0) {
+pub mod FontImageTransformer;
+pub mod atlas;
+pub mod blocks;
+pub mod braille;
+pub mod colour;
+pub mod contact_sheet;
+pub mod dedup;
+pub mod dither;
+pub mod edges;
+pub mod fonts;
+pub mod gradient;
+pub mod histogram;
+pub mod kernel;
+pub mod palette;
+pub mod preprocess;
+pub mod quantize;
+pub mod ramp;
+pub mod style;
+pub mod text;
+pub mod variant;
+
+fn load_font_info(font_data: &[u8]) -> FontInfo {
+
+    let face = match ttf_parser::Face::parse(font_data, 0) {
         Ok(f) => f,
         Err(e) => {
             eprint!("Error: {}.", e);
@@ -109,7 +132,7 @@ fn load_font_info(font_data: &Vec<u8>) -> FontInfo {
         is_variable: format!("{:?}", face.is_variable())
     };
 
-    return font_info;
+    font_info
 }
 
 
@@ -128,7 +151,33 @@ pub fn font_info(file_contents: Uint8Array)  -> Result<JsValue, JsValue> {
     let font_info = load_font_info(&font_data);
     //println!("Elapsed: {}us", now.elapsed().as_micros());
 
-    return to_value(&font_info).map_err(JsValue::from);
+    to_value(&font_info).map_err(JsValue::from)
+}
+
+#[cfg(feature = "opentype-layout")]
+fn print_opentype_layout(name: &str, table: &ttf_parser::opentype_layout::LayoutTable) {
+    println!("OpenType {}:", name);
+    println!("  Scripts:");
+    for script in table.scripts {
+        println!("    {}", script.tag);
+
+        if script.languages.is_empty() {
+            println!("      No languages");
+            continue;
+        }
+
+        println!("      Languages:");
+        for lang in script.languages {
+            println!("        {}", lang.tag);
+        }
+    }
+
+    let mut features: Vec<_> = table.features.into_iter().map(|f| f.tag).collect();
+    features.dedup();
+    println!("  Features:");
+    for feature in features {
+        println!("    {}", feature);
+    }
 }
 
 
@@ -1,8 +1,304 @@
-use ttf_parser::{Face, opentype_layout};
+use ASCII_Renderer::colour::ColourSpace;
+use ASCII_Renderer::FontImageTransformer::{print_eta, render_with_atlas, RenderOptions};
+use ASCII_Renderer::atlas::{AtlasSource, GlyphAtlas};
+use ASCII_Renderer::blocks;
+use ASCII_Renderer::contact_sheet::contact_sheet;
+use ASCII_Renderer::dedup;
+use ASCII_Renderer::fonts::FontStack;
+use ASCII_Renderer::palette::{format_hex, Palette, PaletteRegistry};
+use ASCII_Renderer::preprocess;
+use ASCII_Renderer::quantize::{self, QuantizeMethod};
+use ASCII_Renderer::ramp;
+use ASCII_Renderer::style::GlyphStyle;
+use ASCII_Renderer::text::{self, TextCell};
+use std::path::Path;
+use std::time::Instant;
 
 
+const USAGE: &str = "usage:
+    ASCII-Renderer                            print the font info of Arial-Monospaced
+    ASCII-Renderer palettes                   list the built-in palettes
+    ASCII-Renderer extract-palette <image> [count] [median-cut|k-means|octree] [srgb|linear|lab|oklab]
+                                              suggest background/foreground colours for an image
+    ASCII-Renderer density <font> [charset] [--set <key>=<value>]...
+                                              list the charset (default printable ASCII) by ink,
+                                              with per-quadrant coverage, and the resulting ramp
+    ASCII-Renderer <font> <image> [options]   render ./images/input/<image> into ./images/output/<image>
+    ASCII-Renderer <image> --set block_mode=<half|quadrant|sextant|braille> [options]
+                                              render in block characters, without a font
+    ASCII-Renderer <font> <image> --set ramp=\" .:-=+*#%@\" [--set ramp_auto=true] [options]
+                                              quick render picking characters by brightness
+
+options:
+    --background <palette>   glyph background palette name or file (.hex, .txt, .gpl, .ase, .json)
+    --foreground <palette>   glyph foreground palette name or file
+                             generated palettes: gray-scale:<n>, gradations:<n>,
+                             gradient:<n>:<srgb|linear|lab|oklch>:<#hex>,<#hex>...
+    --fallback <font>        font for characters of the charset option that the fonts before it
+                             lack, repeatable, e.g. --fallback DejaVuSans.ttf --set charset=\" .:-=+*#░▒▓█\"
+    --bold <font>            bold cut of <font>, used for --set glyph_styles=[\"bold\"] instead of
+                             emboldening the outlines (also --italic, --bold-italic)
+    --preprocess <stages>    comma separated image stages run before matching, e.g.
+                             grayscale, brightness-contrast:<b>:<c>, gamma:<g>, auto-levels[:<clip>],
+                             equalize, clahe[:<tiles>[:<limit>]], unsharp[:<sigma>[:<amount>[:<threshold>]]],
+                             blur:<sigma>, invert, posterize:<levels>
+    --atlas-cache <dir>      where glyph atlases are cached, default ./images/atlas
+    --no-atlas-cache         always rasterise the glyphs
+    --contact-sheet <path>   also save every candidate glyph render, labelled, to <path>
+    --merge-report <path>    list the glyphs left out as duplicates in <path>
+                             (near duplicates too with --set prune_distance=0.005)
+    --text <path>            also write the render as plain text to <path> (not for ramp renders)
+    --ansi <path>            also write it as text with 24-bit colour and bold/italic escapes
+    --html <path>            also write it as an HTML <pre> block with styled spans
+    --set <key>=<value>      set a render option, e.g. --set edge_weight=0.5 --set dither=\"atkinson\"";
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => print_font_info(),
+        Some("palettes") => list_palettes(),
+        Some("extract-palette") => {
+            if let Err(message) = extract_palette(&args[1..]) {
+                eprintln!("Error: {}.\n\n{}", message, USAGE);
+                std::process::exit(1);
+            }
+        }
+        Some("density") => {
+            if let Err(message) = print_density(&args[1..]) {
+                eprintln!("Error: {}.\n\n{}", message, USAGE);
+                std::process::exit(1);
+            }
+        }
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        Some(_) => {
+            if let Err(message) = render_image(&args) {
+                eprintln!("Error: {}.\n\n{}", message, USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn list_palettes() {
+    let registry = PaletteRegistry::new();
+    for name in registry.names() {
+        let palette = registry.get(&name).unwrap();
+        println!("{:<20} {}", name, palette.to_hex().join(" "));
+    }
+}
+
+fn extract_palette(args: &[String]) -> Result<(), String> {
+    let image_path = args.first().ok_or("expected <image>")?;
+    let count = args.get(1).map(|c| c.parse::<usize>()).transpose().map_err(|e| e.to_string())?.unwrap_or(5);
+    let method = args.get(2).map(String::as_str).unwrap_or("k-means");
+    let method = QuantizeMethod::from_name(method).ok_or(format!("unknown method {:?}", method))?;
+    let space = match args.get(3) {
+        None => method.default_space(),
+        Some(name) => ColourSpace::from_name(name).ok_or(format!("unknown colour space {:?}", name))?,
+    };
+
+    let img = image::open(image_path).map_err(|e| e.to_string())?;
+    let (background, foreground) = quantize::extract_palette(&img, count, method, space);
+    println!("background: {}", background.into_iter().map(format_hex).collect::<Vec<_>>().join(" "));
+    println!("foreground: {}", foreground.into_iter().map(format_hex).collect::<Vec<_>>().join(" "));
+    Ok(())
+}
+
+fn print_density(args: &[String]) -> Result<(), String> {
+    let mut positional = Vec::new();
+    let mut option_pairs = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--set" => option_pairs.push(iter.next().cloned().ok_or("missing value for --set")?),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let font_path = positional.first().ok_or("expected <font>")?;
+    let charset = positional.get(1).cloned().unwrap_or_else(ramp::printable_ascii);
+    let options = parse_options(&option_pairs)?;
+
+    let font_data = std::fs::read(font_path).map_err(|e| format!("{}: {}", font_path, e))?;
+    let fonts = FontStack::new(vec![font_data]).map_err(|e| e.to_string())?;
+    report_missing(&fonts, &charset);
+    let densities = ramp::glyph_densities(&fonts, &charset, &options).map_err(|e| e.to_string())?;
+
+    for d in &densities {
+        let [tl, tr, bl, br] = d.quadrants;
+        println!("{:?}\t{:.4}\t{:.3} {:.3} {:.3} {:.3}", d.character, d.coverage, tl, tr, bl, br);
+    }
+    println!("ramp: {}", densities.iter().map(|d| d.character).collect::<String>());
+    Ok(())
+}
+
+/// Writes `cells` as plain text, ANSI text and HTML to whichever of the
+/// paths are set.
+fn write_text(cells: &[Vec<TextCell>], [plain, ansi, html]: [&Option<String>; 3]) -> Result<(), String> {
+    let write = |path: &Option<String>, text: fn(&[Vec<TextCell>]) -> String| match path {
+        Some(path) => std::fs::write(path, text(cells)).map_err(|e| format!("{}: {}", path, e)),
+        None => Ok(()),
+    };
+    write(plain, text::plain_text)?;
+    write(ansi, text::ansi_text)?;
+    write(html, text::html_text)
+}
+
+/// Lists the characters of `charset` that no font has, which renders leave out.
+fn report_missing(fonts: &FontStack, charset: &str) {
+    for c in fonts.resolve_charset(charset).1 {
+        println!("No font has {:?}, leaving it out.", c);
+    }
+}
+
+/// A registered palette name, or a path to a palette file.
+fn resolve_palette(registry: &PaletteRegistry, name: &str) -> Result<Palette, String> {
+    match registry.get(name) {
+        Ok(palette) => Ok(palette),
+        Err(_) if std::path::Path::new(name).exists() => {
+            Palette::load(std::path::Path::new(name)).map_err(|e| format!("{}: {}", name, e))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Builds `RenderOptions` from `key=value` pairs. Values are read as JSON,
+/// anything that isn't valid JSON is taken as a string.
+fn parse_options(pairs: &[String]) -> Result<RenderOptions, String> {
+    let mut object = serde_json::Map::new();
+    for pair in pairs {
+        let (key, value) = pair.split_once('=').ok_or(format!("expected key=value, got {:?}", pair))?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        object.insert(key.to_string(), value);
+    }
+
+    serde_json::from_value(serde_json::Value::Object(object)).map_err(|e| e.to_string())
+}
+
+fn render_image(args: &[String]) -> Result<(), String> {
+    let registry = PaletteRegistry::new();
+    let mut positional = Vec::new();
+    let mut background = "default-background".to_string();
+    let mut foreground = "default-foreground".to_string();
+    let mut option_pairs = Vec::new();
+    let mut stages = Vec::new();
+    let mut atlas_cache = Some("./images/atlas".to_string());
+    let mut sheet_path = None;
+    let mut report_path = None;
+    let mut text_path = None;
+    let mut ansi_path = None;
+    let mut html_path = None;
+    let mut fallbacks = Vec::new();
+    let mut style_fonts = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or(format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--background" => background = value()?,
+            "--foreground" => foreground = value()?,
+            "--set" => option_pairs.push(value()?),
+            "--atlas-cache" => atlas_cache = Some(value()?),
+            "--no-atlas-cache" => atlas_cache = None,
+            "--contact-sheet" => sheet_path = Some(value()?),
+            "--merge-report" => report_path = Some(value()?),
+            "--text" => text_path = Some(value()?),
+            "--ansi" => ansi_path = Some(value()?),
+            "--html" => html_path = Some(value()?),
+            "--fallback" => fallbacks.push(value()?),
+            "--bold" => style_fonts.push((GlyphStyle::Bold, value()?)),
+            "--italic" => style_fonts.push((GlyphStyle::Italic, value()?)),
+            "--bold-italic" => style_fonts.push((GlyphStyle::BoldItalic, value()?)),
+            "--preprocess" => stages.extend(preprocess::parse_stages(&value()?).map_err(|e| e.to_string())?),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let mut options = parse_options(&option_pairs)?;
+    options.preprocess.extend(stages);
+
+    if let (Some(mode), [.., image_name]) = (options.block_mode, positional.as_slice()) {
+        if sheet_path.is_some() || report_path.is_some() {
+            return Err("block_mode renders have no glyph atlas to report on".to_string());
+        }
+        let img = image::open("./images/input/".to_owned() + image_name).map_err(|e| e.to_string())?;
+        let start_time = Instant::now();
+        let (out, cells) = blocks::render_blocks(&img, mode, &options, &mut |completed, total| {
+            print_eta(completed, total, start_time);
+        });
+        write_text(&text::block_text(&cells, mode), [&text_path, &ansi_path, &html_path])?;
+        return out.save("./images/output/".to_owned() + image_name).map_err(|e| e.to_string());
+    }
+
+    let [font_path, image_name] = positional.as_slice() else {
+        return Err("expected <font> <image>".to_string());
+    };
+    let glyph_background = resolve_palette(&registry, &background)?.colours;
+    let glyph_colours = resolve_palette(&registry, &foreground)?.colours;
+
+    let font_data = std::iter::once(font_path)
+        .chain(&fallbacks)
+        .map(|path| std::fs::read(path).map_err(|e| format!("{}: {}", path, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut fonts = FontStack::new(font_data).map_err(|e| e.to_string())?;
+    for (style, path) in style_fonts {
+        let data = std::fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+        fonts.add_style(style, data).map_err(|e| e.to_string())?;
+    }
+    let img = image::open("./images/input/".to_owned() + image_name).map_err(|e| e.to_string())?;
+
+    let start_time = Instant::now(); // Record the start time before the loop starts
+
+    if let Some(characters) = &options.ramp {
+        if text_path.is_some() || ansi_path.is_some() || html_path.is_some() {
+            return Err("ramp renders can't be written as text".to_string());
+        }
+        report_missing(&fonts, characters);
+        let out = ramp::render_ramp(&fonts, &img, characters, &options, &mut |completed, total| {
+            print_eta(completed, total, start_time);
+        }).map_err(|e| e.to_string())?;
+        return out.save("./images/output/".to_owned() + image_name).map_err(|e| e.to_string());
+    }
+
+    if let Some(charset) = &options.charset {
+        report_missing(&fonts, charset);
+    }
+    let atlas = match &atlas_cache {
+        Some(dir) => {
+            let (atlas, source) = GlyphAtlas::load_or_build(Path::new(dir), &fonts, &glyph_background, &glyph_colours, &options)
+                .map_err(|e| e.to_string())?;
+            match source {
+                AtlasSource::Cached => println!("Using cached glyph atlas {}.", atlas.key),
+                AtlasSource::Built => {}
+                AtlasSource::Unsaved(e) => eprintln!("Could not cache glyph atlas in {}: {}", dir, e),
+            }
+            atlas
+        }
+        None => GlyphAtlas::build(String::new(), &fonts, &glyph_background, &glyph_colours, &options).map_err(|e| e.to_string())?,
+    };
+    println!("Cell: {} x {} pixels, {} glyph renders.", atlas.cell.width, atlas.cell.height, atlas.glyphs.len());
+    if !atlas.merged.is_empty() {
+        println!("Merged {} glyphs into identical or similar ones.", atlas.merged.len());
+    }
+
+    if let Some(path) = &sheet_path {
+        contact_sheet(&atlas, &fonts).save(path).map_err(|e| e.to_string())?;
+    }
+    if let Some(path) = &report_path {
+        let report = dedup::report(&atlas.merged, &fonts.codepoints());
+        std::fs::write(path, report).map_err(|e| e.to_string())?;
+    }
+
+    let (out, matches) = render_with_atlas(&img, &atlas, &options, &mut |completed, total| {
+        print_eta(completed, total, start_time);
+    });
+    if text_path.is_some() || ansi_path.is_some() || html_path.is_some() {
+        write_text(&text::atlas_text(&matches, &atlas, &fonts.codepoints()), [&text_path, &ansi_path, &html_path])?;
+    }
+
+    out.save("./images/output/".to_owned() + image_name).map_err(|e| e.to_string())
+}
 
+fn print_font_info() {
     let font_data = std::fs::read("./fonts/Arial-Monospaced.ttf").unwrap();
 
     let now = std::time::Instant::now();
@@ -85,6 +381,7 @@ fn main() {
     println!("Elapsed: {}us", now.elapsed().as_micros());
 }
 
+#[cfg(feature = "opentype-layout")]
 fn print_opentype_layout(name: &str, table: &ttf_parser::opentype_layout::LayoutTable) {
     println!("OpenType {}:", name);
     println!("  Scripts:");
@@ -0,0 +1,556 @@
+use image::Rgba;
+use js_sys::Uint8Array;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+use crate::gradient::{gradient, GradientSpace};
+
+// Colour palettes.
+//
+// A palette is a named list of colours used either for glyph backgrounds or
+// glyph foregrounds. The built-in schemes are generated on demand; anything
+// else is parsed from a hex list, a GIMP `.gpl`, an Adobe `.ase` swatch file
+// or JSON. `PaletteRegistry` is what the CLI and wasm API look names up in.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub colours: Vec<Rgba<u8>>,
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    UnknownName(String),
+    UnknownFormat(String),
+    Parse(String),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::UnknownName(name) => write!(f, "Unknown palette: {}", name),
+            PaletteError::UnknownFormat(format) => write!(f, "Unknown palette format: {}", format),
+            PaletteError::Parse(message) => write!(f, "Could not parse palette: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+    Hex,
+    Gpl,
+    Ase,
+    Json,
+}
+
+impl PaletteFormat {
+    /// Accepts a format name or a file extension.
+    pub fn from_name(name: &str) -> Result<PaletteFormat, PaletteError> {
+        match name.trim_start_matches('.').to_ascii_lowercase().as_str() {
+            "hex" | "txt" => Ok(PaletteFormat::Hex),
+            "gpl" => Ok(PaletteFormat::Gpl),
+            "ase" => Ok(PaletteFormat::Ase),
+            "json" => Ok(PaletteFormat::Json),
+            _ => Err(PaletteError::UnknownFormat(name.to_string())),
+        }
+    }
+}
+
+impl Palette {
+    pub fn new(name: &str, colours: Vec<Rgba<u8>>) -> Palette {
+        Palette { name: name.to_string(), colours }
+    }
+
+    pub fn parse(name: &str, data: &[u8], format: PaletteFormat) -> Result<Palette, PaletteError> {
+        let text = || std::str::from_utf8(data).map_err(|e| PaletteError::Parse(e.to_string()));
+
+        match format {
+            PaletteFormat::Hex => parse_hex_list(name, text()?),
+            PaletteFormat::Gpl => parse_gpl(name, text()?),
+            PaletteFormat::Ase => parse_ase(name, data),
+            PaletteFormat::Json => parse_json(name, text()?),
+        }
+    }
+
+    /// Loads a palette file, the format is taken from the extension.
+    pub fn load(path: &std::path::Path) -> Result<Palette, PaletteError> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let format = PaletteFormat::from_name(extension)?;
+        let data = std::fs::read(path).map_err(|e| PaletteError::Parse(e.to_string()))?;
+        let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("palette");
+
+        Palette::parse(name, &data, format)
+    }
+
+    /// Colours packed as RGBA bytes, the layout the wasm API uses.
+    pub fn to_rgba_bytes(&self) -> Vec<u8> {
+        self.colours.iter().flat_map(|c| c.0).collect()
+    }
+
+    pub fn to_hex(&self) -> Vec<String> {
+        self.colours.iter().map(|c| format_hex(*c)).collect()
+    }
+}
+
+pub fn format_hex(colour: Rgba<u8>) -> String {
+    let [r, g, b, a] = colour.0;
+    if a == 255 {
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    } else {
+        format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+/// Parses `#rgb`, `#rrggbb` or `#rrggbbaa`, the `#` is optional.
+pub fn parse_hex(text: &str) -> Result<Rgba<u8>, PaletteError> {
+    let hex = text.trim().trim_start_matches('#');
+    let err = || PaletteError::Parse(format!("bad hex colour {:?}", text));
+    let byte = |i: usize| u8::from_str_radix(hex.get(i..i + 2).ok_or_else(err)?, 16).map_err(|_| err());
+
+    match hex.len() {
+        3 => {
+            let mut c = [255; 4];
+            for (i, ch) in hex.chars().enumerate() {
+                let v = ch.to_digit(16).ok_or_else(err)? as u8;
+                c[i] = v * 17;
+            }
+            Ok(Rgba(c))
+        }
+        6 => Ok(Rgba([byte(0)?, byte(2)?, byte(4)?, 255])),
+        8 => Ok(Rgba([byte(0)?, byte(2)?, byte(4)?, byte(6)?])),
+        _ => Err(err()),
+    }
+}
+
+/// Hex colours separated by whitespace, commas or new lines. Lines starting
+/// with `;` or `//` are comments.
+fn parse_hex_list(name: &str, text: &str) -> Result<Palette, PaletteError> {
+    let mut colours = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with(';') || line.starts_with("//") {
+            continue;
+        }
+        for token in line.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()) {
+            colours.push(parse_hex(token)?);
+        }
+    }
+
+    Ok(Palette::new(name, colours))
+}
+
+fn parse_gpl(name: &str, text: &str) -> Result<Palette, PaletteError> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        return Err(PaletteError::Parse("missing GIMP Palette header".to_string()));
+    }
+
+    let mut palette = Palette::new(name, Vec::new());
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Columns:") {
+            continue;
+        }
+        if let Some(n) = line.strip_prefix("Name:") {
+            palette.name = n.trim().to_string();
+            continue;
+        }
+
+        let channels: Vec<u8> = line
+            .split_whitespace()
+            .take(3)
+            .map(|v| v.parse::<u8>().map_err(|_| PaletteError::Parse(format!("bad gpl line {:?}", line))))
+            .collect::<Result<_, _>>()?;
+        if channels.len() != 3 {
+            return Err(PaletteError::Parse(format!("bad gpl line {:?}", line)));
+        }
+        palette.colours.push(Rgba([channels[0], channels[1], channels[2], 255]));
+    }
+
+    Ok(palette)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonPalette {
+    List(Vec<String>),
+    Named { name: Option<String>, colours: Vec<String> },
+}
+
+/// Either `["#rrggbb", ...]` or `{"name": ..., "colours": [...]}`.
+fn parse_json(name: &str, text: &str) -> Result<Palette, PaletteError> {
+    let parsed: JsonPalette = serde_json::from_str(text).map_err(|e| PaletteError::Parse(e.to_string()))?;
+    let (name, colours) = match parsed {
+        JsonPalette::List(colours) => (name.to_string(), colours),
+        JsonPalette::Named { name: n, colours } => (n.unwrap_or_else(|| name.to_string()), colours),
+    };
+
+    let colours = colours.iter().map(|c| parse_hex(c)).collect::<Result<_, _>>()?;
+    Ok(Palette::new(&name, colours))
+}
+
+/// Adobe Swatch Exchange: big endian, a list of colour / group blocks.
+fn parse_ase(name: &str, data: &[u8]) -> Result<Palette, PaletteError> {
+    let err = |m: &str| PaletteError::Parse(format!("ase: {}", m));
+    let mut r = AseReader { data, pos: 0 };
+
+    if r.bytes(4).ok_or_else(|| err("truncated"))? != b"ASEF" {
+        return Err(err("missing ASEF signature"));
+    }
+    r.bytes(4).ok_or_else(|| err("truncated"))?; // version
+    let blocks = r.u32().ok_or_else(|| err("truncated"))?;
+
+    let mut colours = Vec::new();
+    for _ in 0..blocks {
+        let kind = r.u16().ok_or_else(|| err("truncated"))?;
+        let length = r.u32().ok_or_else(|| err("truncated"))? as usize;
+        let body = r.bytes(length).ok_or_else(|| err("truncated block"))?;
+
+        // Only colour entries matter, group start/end blocks are skipped.
+        if kind != 0x0001 {
+            continue;
+        }
+
+        let mut b = AseReader { data: body, pos: 0 };
+        let name_len = b.u16().ok_or_else(|| err("truncated colour"))? as usize;
+        b.bytes(name_len * 2).ok_or_else(|| err("truncated colour"))?;
+        let model = b.bytes(4).ok_or_else(|| err("truncated colour"))?;
+
+        let colour = match model {
+            b"RGB " => {
+                let mut rgb = [0; 3];
+                for v in rgb.iter_mut() {
+                    *v = unit_to_byte(b.f32().ok_or_else(|| err("truncated rgb"))?);
+                }
+                Rgba([rgb[0], rgb[1], rgb[2], 255])
+            }
+            b"Gray" => {
+                let v = unit_to_byte(b.f32().ok_or_else(|| err("truncated gray"))?);
+                Rgba([v, v, v, 255])
+            }
+            b"CMYK" => {
+                let mut cmyk = [0.0; 4];
+                for v in cmyk.iter_mut() {
+                    *v = b.f32().ok_or_else(|| err("truncated cmyk"))?;
+                }
+                let k = 1.0 - cmyk[3];
+                Rgba([
+                    unit_to_byte((1.0 - cmyk[0]) * k),
+                    unit_to_byte((1.0 - cmyk[1]) * k),
+                    unit_to_byte((1.0 - cmyk[2]) * k),
+                    255,
+                ])
+            }
+            _ => return Err(err("unsupported colour model")),
+        };
+        colours.push(colour);
+    }
+
+    Ok(Palette::new(name, colours))
+}
+
+fn unit_to_byte(v: f32) -> u8 {
+    (v.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+struct AseReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> AseReader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+}
+
+
+fn hacker_green() -> Vec<Rgba<u8>> {
+    vec![
+        Rgba([20,148,29,255]),
+        Rgba([20,107,14,255]),
+        Rgba([100,149,104,255]),
+        Rgba([156,204,156,255]),
+        Rgba([43,83,41,255]),
+    ]
+}
+
+fn plotlogic() -> Vec<Rgba<u8>> {
+    vec![
+        Rgba([31,52,115,255]),
+        Rgba([41,83,166,255]),
+        Rgba([50,186,217,255]),
+        Rgba([27,166,152,255]),
+    ]
+}
+
+fn plotlogic_extended() -> Vec<Rgba<u8>> {
+    let mut colours = plotlogic();
+    colours.extend([
+        Rgba([27,50,106,255]),
+        Rgba([75,190,211,255]),
+        Rgba([21,157,158,255]),
+        Rgba([4,15,55,255]),
+        Rgba([204,218,226,255]),
+        Rgba([18,38,87,255]),
+        Rgba([47,170,215,255]),
+        Rgba([44,116,183,255]),
+        Rgba([110,136,162,255]),
+    ]);
+    colours
+}
+
+/// `gradations` evenly spaced greys from black to white.
+pub fn gray_scale(gradations: u8) -> Vec<Rgba<u8>> {
+    gradient(&[Rgba([0,0,0,255]), Rgba([255,255,255,255])], gradations as usize, GradientSpace::Srgb)
+}
+
+/// Red to green, green to blue and blue to red ramps of `gradations` colours
+/// each.
+pub fn gradations(gradations: u8) -> Vec<Rgba<u8>> {
+    let (red, green, blue) = (Rgba([255,0,0,255]), Rgba([0,255,0,255]), Rgba([0,0,255,255]));
+
+    let mut colours = Vec::new();
+    for (from, to) in [(green, red), (blue, green), (red, blue)] {
+        colours.extend(gradient(&[from, to], gradations as usize, GradientSpace::Srgb));
+    }
+    colours
+}
+
+/// `count:space:stop,stop...`, e.g. `16:oklch:#ff0000,#0000ff`.
+fn parse_gradient(spec: &str) -> Option<Vec<Rgba<u8>>> {
+    let mut parts = spec.splitn(3, ':');
+    let count: usize = parts.next()?.parse().ok()?;
+    let space = GradientSpace::from_name(parts.next()?)?;
+    let stops = parts.next()?.split(',').map(|s| parse_hex(s.trim()).ok()).collect::<Option<Vec<_>>>()?;
+    Some(gradient(&stops, count, space))
+}
+
+/// Named palettes. Parameterised built-ins take their argument after a
+/// colon, e.g. `gray-scale:7` or `gradient:16:oklch:#ff0000,#0000ff`.
+pub struct PaletteRegistry {
+    palettes: BTreeMap<String, Palette>,
+}
+
+impl Default for PaletteRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PaletteRegistry {
+    /// A registry holding the built-in palettes.
+    pub fn new() -> PaletteRegistry {
+        let mut registry = PaletteRegistry { palettes: BTreeMap::new() };
+
+        // Four and eight evenly spaced greys. These used to be gray_scale(3)
+        // plus white and black plus gray_scale(7), which repeated black or
+        // white and topped the foreground greys out at 252.
+        registry.register(Palette::new("default-background", gray_scale(4)));
+        registry.register(Palette::new("default-foreground", gray_scale(8)));
+        registry.register(Palette::new("black", vec![Rgba([0,0,0,255])]));
+        registry.register(Palette::new("white", vec![Rgba([255,255,255,255])]));
+        registry.register(Palette::new("hacker-green", hacker_green()));
+        registry.register(Palette::new("plotlogic", plotlogic()));
+        registry.register(Palette::new("plotlogic-extended", plotlogic_extended()));
+        registry.register(Palette::new("gray-scale", gray_scale(3)));
+        registry.register(Palette::new("gradations", gradations(3)));
+
+        registry
+    }
+
+    pub fn register(&mut self, palette: Palette) {
+        self.palettes.insert(palette.name.clone(), palette);
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.palettes.keys().cloned().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Palette, PaletteError> {
+        if let Some((base, argument)) = name.split_once(':') {
+            let unknown = || PaletteError::UnknownName(name.to_string());
+            let colours = match base {
+                "gray-scale" => gray_scale(argument.parse().map_err(|_| unknown())?),
+                "gradations" => gradations(argument.parse().map_err(|_| unknown())?),
+                "gradient" => parse_gradient(argument).ok_or_else(unknown)?,
+                _ => return Err(unknown()),
+            };
+            return Ok(Palette::new(name, colours));
+        }
+
+        self.palettes
+            .get(name)
+            .cloned()
+            .ok_or_else(|| PaletteError::UnknownName(name.to_string()))
+    }
+}
+
+
+#[wasm_bindgen]
+pub fn palette_names() -> Vec<JsValue> {
+    PaletteRegistry::new().names().into_iter().map(JsValue::from).collect()
+}
+
+/// A built-in palette as packed RGBA bytes.
+#[wasm_bindgen]
+pub fn palette_colours(name: &str) -> Result<Vec<u8>, JsValue> {
+    PaletteRegistry::new()
+        .get(name)
+        .map(|p| p.to_rgba_bytes())
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Parses palette file contents (`hex`, `gpl`, `ase` or `json`) into packed
+/// RGBA bytes.
+#[wasm_bindgen]
+pub fn parse_palette(file_contents: Uint8Array, format: &str) -> Result<Vec<u8>, JsValue> {
+    let format = PaletteFormat::from_name(format).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Palette::parse("palette", &file_contents.to_vec(), format)
+        .map(|p| p.to_rgba_bytes())
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gray_scale_has_exact_count() {
+        for n in 0..=255u8 {
+            let colours = gray_scale(n);
+            assert_eq!(colours.len(), n as usize);
+            if n >= 2 {
+                assert_eq!(colours[0], Rgba([0,0,0,255]));
+                assert_eq!(colours[n as usize - 1], Rgba([255,255,255,255]));
+            }
+        }
+
+        let levels: Vec<u8> = gray_scale(3).iter().map(|c| c[0]).collect();
+        assert_eq!(levels, vec![0, 128, 255]);
+    }
+
+    #[test]
+    fn gradations_handles_small_counts() {
+        assert!(gradations(0).is_empty());
+        assert_eq!(gradations(1), vec![Rgba([0,255,0,255]), Rgba([0,0,255,255]), Rgba([255,0,0,255])]);
+        assert_eq!(gradations(3).len(), 9);
+        assert_eq!(gradations(255).len(), 765);
+    }
+
+    #[test]
+    fn registry_gradient() {
+        let registry = PaletteRegistry::new();
+        let palette = registry.get("gradient:5:oklch:#ff0000,#ffffff,#0000ff").unwrap();
+        assert_eq!(palette.colours.len(), 5);
+        assert_eq!(palette.colours[2], Rgba([255,255,255,255]));
+
+        assert!(registry.get("gradient:5:hsv:#ff0000,#0000ff").is_err());
+        assert!(registry.get("gradient:5:lab:#ff0000,nope").is_err());
+        assert_eq!(registry.get("gray-scale:7").unwrap().colours.len(), 7);
+    }
+
+    /// An ASE file of `blocks`, each a block type and body.
+    fn ase(blocks: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut data = b"ASEF\0\x01\0\0".to_vec();
+        data.extend((blocks.len() as u32).to_be_bytes());
+        for (kind, body) in blocks {
+            data.extend(kind.to_be_bytes());
+            data.extend((body.len() as u32).to_be_bytes());
+            data.extend(body);
+        }
+        data
+    }
+
+    /// A colour block body named "c" in `model` with `values`.
+    fn ase_colour(model: &[u8; 4], values: &[f32]) -> Vec<u8> {
+        let mut body = vec![0, 2, 0, b'c', 0, 0];
+        body.extend(model);
+        body.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+        body.extend([0, 2]);
+        body
+    }
+
+    #[test]
+    fn parses_hex_lists() {
+        let text = "; comment\n#ff0000, 00ff00\n// another\n#00f #11223344\n";
+        let palette = Palette::parse("list", text.as_bytes(), PaletteFormat::Hex).unwrap();
+        assert_eq!(palette.colours, vec![
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 255, 0, 255]),
+            Rgba([0, 0, 255, 255]),
+            Rgba([0x11, 0x22, 0x33, 0x44]),
+        ]);
+
+        for bad in ["#ff0000 nonsense", "#12345", "#gg0000", "#ééé"] {
+            assert!(Palette::parse("list", bad.as_bytes(), PaletteFormat::Hex).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_gpl() {
+        let text = "GIMP Palette\nName: Test\nColumns: 2\n# comment\n255 0 0 Red\n  0 0 255\tBlue\n";
+        let palette = Palette::parse("file", text.as_bytes(), PaletteFormat::Gpl).unwrap();
+        assert_eq!(palette.name, "Test");
+        assert_eq!(palette.colours, vec![Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])]);
+
+        for bad in ["255 0 0\n", "GIMP Palette\n255 0\n", "GIMP Palette\n256 0 0\n", "GIMP Palette\nred green blue\n"] {
+            assert!(Palette::parse("file", bad.as_bytes(), PaletteFormat::Gpl).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_json() {
+        let list = Palette::parse("file", br##"["#000", "#ffffff"]"##, PaletteFormat::Json).unwrap();
+        assert_eq!((list.name.as_str(), list.colours.len()), ("file", 2));
+        let named = Palette::parse("file", br##"{"name": "Named", "colours": ["#ff0000"]}"##, PaletteFormat::Json).unwrap();
+        assert_eq!(named.name, "Named");
+        assert_eq!(named.colours, vec![Rgba([255, 0, 0, 255])]);
+
+        for bad in [&br##"["#000", "#nope"]"##[..], br##"["#000""##, br##"{"colours": 3}"##] {
+            assert!(Palette::parse("file", bad, PaletteFormat::Json).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_ase() {
+        let data = ase(&[
+            (0xc001, vec![0, 1, 0, 0]),
+            (0x0001, ase_colour(b"RGB ", &[1.0, 0.0, 0.5])),
+            (0x0001, ase_colour(b"Gray", &[0.5])),
+            (0x0001, ase_colour(b"CMYK", &[0.0, 1.0, 1.0, 0.0])),
+            (0xc002, Vec::new()),
+        ]);
+        let palette = Palette::parse("file", &data, PaletteFormat::Ase).unwrap();
+        assert_eq!(palette.colours, vec![Rgba([255, 0, 128, 255]), Rgba([128, 128, 128, 255]), Rgba([255, 0, 0, 255])]);
+
+        // Cut anywhere, the file is rejected rather than read in part.
+        for end in 0..data.len() {
+            assert!(Palette::parse("file", &data[..end], PaletteFormat::Ase).is_err(), "cut at {}", end);
+        }
+        // A colour block whose values run past its length.
+        let short = ase(&[(0x0001, ase_colour(b"RGB ", &[1.0])[..10].to_vec())]);
+        assert!(Palette::parse("file", &short, PaletteFormat::Ase).is_err());
+        let lab = ase(&[(0x0001, ase_colour(b"LAB ", &[50.0, 0.0, 0.0]))]);
+        assert!(Palette::parse("file", &lab, PaletteFormat::Ase).is_err());
+        assert!(Palette::parse("file", b"ASEX\0\x01\0\0\0\0\0\0", PaletteFormat::Ase).is_err());
+    }
+}
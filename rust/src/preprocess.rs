@@ -0,0 +1,447 @@
+use image::{imageops, DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba, RgbImage, RgbaImage};
+use js_sys::Uint8Array;
+use serde::Deserialize;
+use std::fmt;
+use std::ops::RangeInclusive;
+use wasm_bindgen::prelude::*;
+
+use crate::FontImageTransformer::encode_png;
+
+// Image preprocessing.
+//
+// A chain of stages applied to the input before it is tiled. Stages run in
+// order on an RGBA copy; alpha is left alone. The tonal stages (auto levels,
+// equalization, CLAHE) work on luma and shift every channel by the same
+// amount so hues survive. Stages come from `RenderOptions::preprocess` as
+// JSON objects (`{"stage": "gamma", "gamma": 2.2}`) or from the compact CLI
+// form parsed by `parse_stages` (`gamma:2.2,clahe:8:2`).
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "stage", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Stage {
+    Grayscale,
+    /// `brightness` is added as a fraction of full scale (`-1..1`),
+    /// `contrast` scales around mid grey (`1` leaves it unchanged).
+    BrightnessContrast {
+        #[serde(default)]
+        brightness: f32,
+        #[serde(default = "one")]
+        contrast: f32,
+    },
+    /// Values above 1 brighten the mid tones.
+    Gamma { gamma: f32 },
+    /// Stretches luma so the darkest and brightest `clip` fraction of pixels
+    /// hit black and white.
+    AutoLevels {
+        #[serde(default = "default_clip")]
+        clip: f32,
+    },
+    Equalize,
+    /// Contrast limited adaptive histogram equalization on a `tiles` x
+    /// `tiles` grid. `clip_limit` is in multiples of an even histogram.
+    Clahe {
+        #[serde(default = "default_tiles")]
+        tiles: u32,
+        #[serde(default = "default_clip_limit")]
+        clip_limit: f32,
+    },
+    /// Adds `amount` times the difference to a Gaussian blur, ignoring
+    /// differences up to `threshold`.
+    Unsharp {
+        #[serde(default = "one")]
+        sigma: f32,
+        #[serde(default = "one")]
+        amount: f32,
+        #[serde(default)]
+        threshold: u8,
+    },
+    Blur { sigma: f32 },
+    Invert,
+    Posterize { levels: u8 },
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn default_clip() -> f32 {
+    0.005
+}
+
+fn default_tiles() -> u32 {
+    8
+}
+
+fn default_clip_limit() -> f32 {
+    2.0
+}
+
+#[derive(Debug)]
+pub enum StageError {
+    UnknownStage(String),
+    BadArgument(String),
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StageError::UnknownStage(name) => write!(f, "Unknown preprocessing stage: {}", name),
+            StageError::BadArgument(spec) => write!(f, "Bad preprocessing stage arguments: {}", spec),
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+impl Stage {
+    /// Parses `name[:arg[:arg...]]`, arguments in the order the fields are
+    /// declared. Trailing arguments with defaults may be left out. Integer
+    /// arguments outside their field's range are an error.
+    pub fn parse(spec: &str) -> Result<Stage, StageError> {
+        let mut parts = spec.trim().split(':');
+        let name = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+
+        let bad = || StageError::BadArgument(spec.to_string());
+        let arg = |i: usize, default: Option<f32>| -> Result<f32, StageError> {
+            match args.get(i) {
+                Some(v) => v.parse().ok().filter(|v: &f32| v.is_finite()).ok_or_else(bad),
+                None => default.ok_or_else(bad),
+            }
+        };
+        let int = |i: usize, default: Option<u32>, range: RangeInclusive<u32>| -> Result<u32, StageError> {
+            match args.get(i) {
+                Some(v) => v.parse().ok().filter(|v| range.contains(v)).ok_or_else(bad),
+                None => default.ok_or_else(bad),
+            }
+        };
+
+        let stage = match name {
+            "grayscale" => Stage::Grayscale,
+            "brightness-contrast" => Stage::BrightnessContrast { brightness: arg(0, Some(0.0))?, contrast: arg(1, Some(1.0))? },
+            "gamma" => Stage::Gamma { gamma: arg(0, None)? },
+            "auto-levels" => Stage::AutoLevels { clip: arg(0, Some(default_clip()))? },
+            "equalize" => Stage::Equalize,
+            "clahe" => Stage::Clahe {
+                tiles: int(0, Some(default_tiles()), 1..=256)?,
+                clip_limit: arg(1, Some(default_clip_limit()))?,
+            },
+            "unsharp" => Stage::Unsharp {
+                sigma: arg(0, Some(1.0))?,
+                amount: arg(1, Some(1.0))?,
+                threshold: int(2, Some(0), 0..=255)? as u8,
+            },
+            "blur" => Stage::Blur { sigma: arg(0, None)? },
+            "invert" => Stage::Invert,
+            "posterize" => Stage::Posterize { levels: int(0, None, 2..=255)? as u8 },
+            _ => return Err(StageError::UnknownStage(name.to_string())),
+        };
+
+        let expected = match stage {
+            Stage::Grayscale | Stage::Equalize | Stage::Invert => 0,
+            Stage::Gamma { .. } | Stage::AutoLevels { .. } | Stage::Blur { .. } | Stage::Posterize { .. } => 1,
+            Stage::BrightnessContrast { .. } | Stage::Clahe { .. } => 2,
+            Stage::Unsharp { .. } => 3,
+        };
+        if args.len() > expected {
+            return Err(bad());
+        }
+
+        Ok(stage)
+    }
+
+    pub fn apply(&self, img: &mut RgbaImage) {
+        match *self {
+            Stage::Grayscale => {
+                for p in img.pixels_mut() {
+                    let l = luma(p).round() as u8;
+                    *p = Rgba([l, l, l, p[3]]);
+                }
+            }
+            Stage::BrightnessContrast { brightness, contrast } => {
+                map_channels(img, |v| (v - 127.5) * contrast + 127.5 + brightness * 255.0)
+            }
+            Stage::Gamma { gamma } => {
+                let exponent = 1.0 / gamma.max(f32::EPSILON);
+                map_channels(img, |v| (v / 255.0).powf(exponent) * 255.0)
+            }
+            Stage::AutoLevels { clip } => auto_levels(img, clip),
+            Stage::Equalize => {
+                let lut = equalization_lut(&luma_histogram(img.pixels().copied()), f32::INFINITY);
+                shift_luma(img, |_, _, l| lut[l as usize]);
+            }
+            Stage::Clahe { tiles, clip_limit } => clahe(img, tiles.max(1), clip_limit),
+            Stage::Unsharp { sigma, amount, threshold } => unsharp(img, sigma, amount, threshold),
+            Stage::Blur { sigma } => {
+                if sigma > 0.0 {
+                    let blurred = blur_rgb(img, sigma);
+                    for (p, b) in img.pixels_mut().zip(blurred.pixels()) {
+                        p.0[..3].copy_from_slice(&b.0);
+                    }
+                }
+            }
+            Stage::Invert => imageops::invert(img),
+            Stage::Posterize { levels } => {
+                let steps = levels.max(2) as f32 - 1.0;
+                map_channels(img, |v| (v / 255.0 * steps).round() / steps * 255.0)
+            }
+        }
+    }
+}
+
+/// Parses a comma separated list of [`Stage::parse`] specs.
+pub fn parse_stages(specs: &str) -> Result<Vec<Stage>, StageError> {
+    specs.split(',').filter(|s| !s.trim().is_empty()).map(Stage::parse).collect()
+}
+
+/// `img` with every stage applied in order.
+pub fn apply(img: &DynamicImage, stages: &[Stage]) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for stage in stages {
+        stage.apply(&mut rgba);
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+pub(crate) fn luma(p: &Rgba<u8>) -> f32 {
+    0.2126 * p[0] as f32 + 0.7152 * p[1] as f32 + 0.0722 * p[2] as f32
+}
+
+fn map_channels(img: &mut RgbaImage, f: impl Fn(f32) -> f32) {
+    let lut: Vec<u8> = (0..256).map(|v| f(v as f32).round().clamp(0.0, 255.0) as u8).collect();
+    for p in img.pixels_mut() {
+        for c in 0..3 {
+            p[c] = lut[p[c] as usize];
+        }
+    }
+}
+
+/// Moves each pixel's luma to `f(x, y, luma)` by shifting all channels.
+fn shift_luma(img: &mut RgbaImage, f: impl Fn(u32, u32, u8) -> f32) {
+    for (x, y, p) in img.enumerate_pixels_mut() {
+        let l = luma(p);
+        let shift = f(x, y, l.round() as u8) - l;
+        for c in 0..3 {
+            p[c] = (p[c] as f32 + shift).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+fn luma_histogram(pixels: impl Iterator<Item = Rgba<u8>>) -> [u32; 256] {
+    let mut histogram = [0u32; 256];
+    for p in pixels {
+        histogram[luma(&p).round() as usize] += 1;
+    }
+    histogram
+}
+
+/// Equalizing luma mapping for `histogram`, with bins clipped at
+/// `clip_limit` times the mean bin and the excess spread over every bin.
+fn equalization_lut(histogram: &[u32; 256], clip_limit: f32) -> [f32; 256] {
+    let total: u32 = histogram.iter().sum();
+    let mut bins = histogram.map(|v| v as f32);
+
+    if clip_limit.is_finite() {
+        let limit = (clip_limit * total as f32 / 256.0).max(1.0);
+        let excess: f32 = bins.iter().map(|&v| (v - limit).max(0.0)).sum();
+        for v in bins.iter_mut() {
+            *v = v.min(limit) + excess / 256.0;
+        }
+    }
+
+    let mut lut = [0.0; 256];
+    let mut cdf = 0.0;
+    for (l, v) in lut.iter_mut().zip(bins) {
+        cdf += v;
+        *l = cdf / total.max(1) as f32 * 255.0;
+    }
+    lut
+}
+
+fn auto_levels(img: &mut RgbaImage, clip: f32) {
+    let histogram = luma_histogram(img.pixels().copied());
+    let total: u32 = histogram.iter().sum();
+    let cut = (total as f32 * clip.clamp(0.0, 0.5)) as u32;
+
+    let mut seen = 0;
+    let low = histogram.iter().position(|&v| { seen += v; seen > cut }).unwrap_or(0);
+    seen = 0;
+    let high = 255 - histogram.iter().rev().position(|&v| { seen += v; seen > cut }).unwrap_or(0);
+    if high <= low {
+        return;
+    }
+
+    let scale = 255.0 / (high - low) as f32;
+    shift_luma(img, |_, _, l| (l as f32 - low as f32) * scale);
+}
+
+fn clahe(img: &mut RgbaImage, tiles: u32, clip_limit: f32) {
+    let (width, height) = img.dimensions();
+    let (tw, th) = (width.div_ceil(tiles).max(1), height.div_ceil(tiles).max(1));
+    let (cols, rows) = (width.div_ceil(tw), height.div_ceil(th));
+
+    let mut luts = Vec::with_capacity((cols * rows) as usize);
+    for row in 0..rows {
+        for col in 0..cols {
+            let tile = imageops::crop_imm(img, col * tw, row * th, tw, th);
+            let histogram = luma_histogram(tile.pixels().map(|(_, _, p)| p));
+            luts.push(equalization_lut(&histogram, clip_limit));
+        }
+    }
+
+    // Bilinear between the mappings of the four nearest tile centres.
+    let lookup = |col: f32, row: f32, l: u8| {
+        let c0 = col.floor().clamp(0.0, cols as f32 - 1.0);
+        let r0 = row.floor().clamp(0.0, rows as f32 - 1.0);
+        let (c1, r1) = ((c0 + 1.0).min(cols as f32 - 1.0), (r0 + 1.0).min(rows as f32 - 1.0));
+        let (fx, fy) = ((col - c0).clamp(0.0, 1.0), (row - r0).clamp(0.0, 1.0));
+        let at = |c: f32, r: f32| luts[(r as u32 * cols + c as u32) as usize][l as usize];
+
+        let top = at(c0, r0) * (1.0 - fx) + at(c1, r0) * fx;
+        let bottom = at(c0, r1) * (1.0 - fx) + at(c1, r1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    };
+
+    shift_luma(img, |x, y, l| {
+        lookup((x as f32 + 0.5) / tw as f32 - 0.5, (y as f32 + 0.5) / th as f32 - 0.5, l)
+    });
+}
+
+/// The colour channels of `img` blurred, leaving alpha out of it.
+fn blur_rgb(img: &RgbaImage, sigma: f32) -> RgbImage {
+    let rgb: RgbImage = ImageBuffer::from_fn(img.width(), img.height(), |x, y| img.get_pixel(x, y).to_rgb());
+    imageops::blur(&rgb, sigma)
+}
+
+fn unsharp(img: &mut RgbaImage, sigma: f32, amount: f32, threshold: u8) {
+    if sigma <= 0.0 {
+        return;
+    }
+
+    let blurred = blur_rgb(img, sigma);
+    for (p, b) in img.pixels_mut().zip(blurred.pixels()) {
+        for c in 0..3 {
+            let diff = p[c] as f32 - b[c] as f32;
+            if diff.abs() > threshold as f32 {
+                p[c] = (p[c] as f32 + diff * amount).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// Runs `stages` (an array of stage objects, see `RenderOptions`) over an
+/// encoded image and returns the result as a PNG, for previews.
+#[wasm_bindgen]
+pub fn preprocess_image(image: Uint8Array, stages: JsValue) -> Result<Vec<u8>, JsValue> {
+    let stages: Vec<Stage> = serde_wasm_bindgen::from_value(stages)?;
+    let img = image::load_from_memory(&image.to_vec())
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    encode_png(&apply(&img, &stages).to_rgba8()).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blur_keeps_alpha() {
+        // An opaque square on a transparent background.
+        let mut img = RgbaImage::from_fn(9, 9, |x, y| match (3..6).contains(&x) && (3..6).contains(&y) {
+            true => Rgba([255, 255, 255, 255]),
+            false => Rgba([0, 0, 0, 0]),
+        });
+        let alpha: Vec<u8> = img.pixels().map(|p| p[3]).collect();
+        Stage::Blur { sigma: 1.5 }.apply(&mut img);
+        assert_eq!(img.pixels().map(|p| p[3]).collect::<Vec<u8>>(), alpha);
+        assert!(img.get_pixel(2, 4)[0] > 0);
+    }
+
+    #[test]
+    fn parses_stages() {
+        assert_eq!(
+            parse_stages("grayscale, gamma:2.2,clahe,unsharp:2,auto-levels,brightness-contrast:0.1").unwrap(),
+            [
+                Stage::Grayscale,
+                Stage::Gamma { gamma: 2.2 },
+                Stage::Clahe { tiles: 8, clip_limit: 2.0 },
+                Stage::Unsharp { sigma: 2.0, amount: 1.0, threshold: 0 },
+                Stage::AutoLevels { clip: 0.005 },
+                Stage::BrightnessContrast { brightness: 0.1, contrast: 1.0 },
+            ]
+        );
+        assert_eq!(Stage::parse("clahe:4:3").unwrap(), Stage::Clahe { tiles: 4, clip_limit: 3.0 });
+        assert_eq!(Stage::parse("posterize:255").unwrap(), Stage::Posterize { levels: 255 });
+        assert_eq!(Stage::parse("unsharp:1:1:255").unwrap(), Stage::Unsharp { sigma: 1.0, amount: 1.0, threshold: 255 });
+    }
+
+    #[test]
+    fn rejects_bad_stages() {
+        assert!(matches!(Stage::parse("sharpen"), Err(StageError::UnknownStage(name)) if name == "sharpen"));
+        for spec in [
+            // Too many arguments.
+            "invert:1", "gamma:1:2", "clahe:8:2:1", "unsharp:1:1:0:0",
+            // Missing or unparsable arguments.
+            "gamma", "blur", "posterize", "gamma:x", "gamma:nan", "blur:inf",
+            // Out of range integers.
+            "posterize:256", "posterize:1", "posterize:-4", "posterize:2.5",
+            "clahe:0", "clahe:-1", "clahe:100000", "unsharp:1:1:256",
+        ] {
+            assert!(matches!(Stage::parse(spec), Err(StageError::BadArgument(_))), "{}", spec);
+        }
+    }
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(256, 1, |x, _| Rgba([x as u8, x as u8, x as u8, 255]))
+    }
+
+    #[test]
+    fn posterize_levels() {
+        let mut img = gradient();
+        Stage::Posterize { levels: 3 }.apply(&mut img);
+        let mut values: Vec<u8> = img.pixels().map(|p| p[0]).collect();
+        values.dedup();
+        assert_eq!(values, [0, 128, 255]);
+
+        let mut img = gradient();
+        Stage::Posterize { levels: 255 }.apply(&mut img);
+        assert!(img.pixels().zip(gradient().pixels()).all(|(a, b)| a[0].abs_diff(b[0]) <= 1));
+    }
+
+    #[test]
+    fn auto_levels_stretches() {
+        // Greys from 64 to 191.
+        let mut img = RgbaImage::from_fn(128, 1, |x, _| Rgba([x as u8 + 64, x as u8 + 64, x as u8 + 64, 200]));
+        Stage::AutoLevels { clip: 0.0 }.apply(&mut img);
+        let values: Vec<u8> = img.pixels().map(|p| p[0]).collect();
+        assert_eq!((values[0], values[127]), (0, 255));
+        assert!(values.windows(2).all(|w| w[0] < w[1]));
+        assert!(img.pixels().all(|p| p[3] == 200));
+
+        // Clipping ignores a few outliers.
+        let mut img = RgbaImage::from_fn(100, 1, |x, _| match x {
+            0 => Rgba([0, 0, 0, 255]),
+            99 => Rgba([255, 255, 255, 255]),
+            _ => Rgba([100 + x as u8 % 2 * 50; 4]),
+        });
+        Stage::AutoLevels { clip: 0.02 }.apply(&mut img);
+        assert_eq!((img.get_pixel(2, 0)[0], img.get_pixel(1, 0)[0]), (0, 255));
+    }
+
+    #[test]
+    fn clahe_raises_local_contrast() {
+        // Two flat halves, each with faint stripes.
+        let mut img = RgbaImage::from_fn(64, 64, |x, y| {
+            let v = if x < 32 { 40 } else { 200 } + (y % 2) as u8 * 8;
+            Rgba([v, v, v, 255])
+        });
+        let spread = |img: &RgbaImage, x| img.get_pixel(x, 1)[0] as i32 - img.get_pixel(x, 0)[0] as i32;
+        Stage::Clahe { tiles: 2, clip_limit: 4.0 }.apply(&mut img);
+        assert!(spread(&img, 8) > 8 && spread(&img, 56) > 8, "{} {}", spread(&img, 8), spread(&img, 56));
+        assert!(img.pixels().all(|p| p[3] == 255 && p[0] == p[1] && p[1] == p[2]));
+
+        // More tiles than pixels.
+        let mut small = RgbaImage::from_pixel(3, 2, Rgba([90, 90, 90, 255]));
+        Stage::Clahe { tiles: 256, clip_limit: 2.0 }.apply(&mut small);
+    }
+}
@@ -0,0 +1,398 @@
+use image::{DynamicImage, GenericImageView, Rgba};
+use js_sys::Uint8Array;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+use crate::colour::ColourSpace;
+use crate::palette::format_hex;
+
+// Palette extraction.
+//
+// Picks N representative colours from an image with median cut, k-means or
+// an octree. Median cut and k-means work in any `ColourSpace`; the octree
+// is built from sRGB bits. `extract_palette` splits the image into flat colour
+// (each block's average, used for glyph backgrounds) and detail (the pixels
+// furthest from their block's average, used for glyph foregrounds) before
+// quantizing each set.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum QuantizeMethod {
+    #[serde(rename = "median-cut")]
+    MedianCut,
+    #[serde(rename = "k-means")]
+    KMeans,
+    #[serde(rename = "octree")]
+    Octree,
+}
+
+impl QuantizeMethod {
+    pub fn from_name(name: &str) -> Option<QuantizeMethod> {
+        match name {
+            "median-cut" => Some(QuantizeMethod::MedianCut),
+            "k-means" => Some(QuantizeMethod::KMeans),
+            "octree" => Some(QuantizeMethod::Octree),
+            _ => None,
+        }
+    }
+
+    /// The space each method used before it could be chosen.
+    pub fn default_space(self) -> ColourSpace {
+        match self {
+            QuantizeMethod::KMeans => ColourSpace::Lab,
+            QuantizeMethod::MedianCut | QuantizeMethod::Octree => ColourSpace::Srgb,
+        }
+    }
+}
+
+// Enough samples for a stable palette without walking every pixel of a photo.
+const MAX_SAMPLES: u32 = 1 << 16;
+
+const BLOCK: u32 = 8;
+
+pub fn quantize(pixels: &[[u8; 3]], n: usize, method: QuantizeMethod, space: ColourSpace) -> Vec<Rgba<u8>> {
+    if pixels.is_empty() || n == 0 {
+        return Vec::new();
+    }
+
+    let colours = match method {
+        QuantizeMethod::MedianCut => median_cut(pixels, n, space),
+        QuantizeMethod::KMeans => kmeans(pixels, n, 16, space),
+        QuantizeMethod::Octree => octree(pixels, n),
+    };
+
+    colours.into_iter().map(|[r, g, b]| Rgba([r, g, b, 255])).collect()
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for p in pixels {
+        for c in 0..3 {
+            sum[c] += p[c] as u64;
+        }
+    }
+    let count = pixels.len().max(1) as u64;
+    sum.map(|s| ((s + count / 2) / count) as u8)
+}
+
+fn average_f32(points: &[[f32; 3]]) -> [f32; 3] {
+    let mut sum = [0f64; 3];
+    for p in points {
+        for c in 0..3 {
+            sum[c] += p[c] as f64;
+        }
+    }
+    let count = points.len().max(1) as f64;
+    sum.map(|s| (s / count) as f32)
+}
+
+/// Repeatedly splits the box with the widest channel range (in `space`) at
+/// its median, moved to the nearest change of value so equal colours stay in
+/// one box. Stops early once every box holds a single colour.
+pub fn median_cut(pixels: &[[u8; 3]], n: usize, space: ColourSpace) -> Vec<[u8; 3]> {
+    let mut boxes: Vec<Vec<[f32; 3]>> = vec![pixels.iter().map(|&p| space.encode(p)).collect()];
+
+    while boxes.len() < n {
+        let range = |b: &Vec<[f32; 3]>, c: usize| {
+            let (min, max) = b.iter().fold((f32::MAX, f32::MIN), |(lo, hi), p| (p[c].min(lo), p[c].max(hi)));
+            max - min
+        };
+
+        // The box and channel with the widest spread.
+        let Some((index, channel, _)) = boxes.iter().enumerate()
+            .flat_map(|(i, b)| (0..3).map(move |c| (i, c, range(b, c))))
+            .filter(|&(_, _, range)| range > 0.0)
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+        else {
+            break;
+        };
+
+        let mut b = boxes.swap_remove(index);
+        b.sort_unstable_by(|p, q| p[channel].total_cmp(&q[channel]));
+        let median = b.len() / 2;
+        let split = (1..b.len())
+            .filter(|&i| b[i - 1][channel] != b[i][channel])
+            .min_by_key(|&i| i.abs_diff(median))
+            .unwrap();
+        let upper = b.split_off(split);
+        boxes.push(b);
+        boxes.push(upper);
+    }
+
+    // Largest boxes first.
+    boxes.sort_by_key(|b| std::cmp::Reverse(b.len()));
+    boxes.iter().map(|b| space.decode(average_f32(b))).collect()
+}
+
+/// Lloyd's k-means in `space`, seeded with the sRGB median cut palette so
+/// the result is deterministic.
+pub fn kmeans(pixels: &[[u8; 3]], n: usize, iterations: usize, space: ColourSpace) -> Vec<[u8; 3]> {
+    let samples: Vec<[f32; 3]> = pixels.iter().map(|&p| space.encode(p)).collect();
+    let mut centres: Vec<[f32; 3]> = median_cut(pixels, n, ColourSpace::Srgb).into_iter().map(|p| space.encode(p)).collect();
+    let mut counts = vec![0usize; centres.len()];
+
+    for _ in 0..iterations {
+        let mut sums = vec![[0f32; 3]; centres.len()];
+        counts = vec![0; centres.len()];
+
+        for s in &samples {
+            let nearest = nearest(&centres, s);
+            for c in 0..3 {
+                sums[nearest][c] += s[c];
+            }
+            counts[nearest] += 1;
+        }
+
+        let mut moved = false;
+        for (i, centre) in centres.iter_mut().enumerate() {
+            if counts[i] == 0 {
+                continue;
+            }
+            let next = sums[i].map(|v| v / counts[i] as f32);
+            moved |= distance2(&next, centre) > 1e-4;
+            *centre = next;
+        }
+
+        if !moved {
+            break;
+        }
+    }
+
+    let mut ranked: Vec<(usize, [f32; 3])> = counts.into_iter().zip(centres).filter(|(c, _)| *c > 0).collect();
+    ranked.sort_by_key(|(c, _)| std::cmp::Reverse(*c));
+    ranked.into_iter().map(|(_, v)| space.decode(v)).collect()
+}
+
+fn distance2(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (0..3).map(|c| (a[c] - b[c]) * (a[c] - b[c])).sum()
+}
+
+fn nearest(centres: &[[f32; 3]], s: &[f32; 3]) -> usize {
+    let mut best = 0;
+    let mut best_distance = f32::MAX;
+    for (i, c) in centres.iter().enumerate() {
+        let d = distance2(c, s);
+        if d < best_distance {
+            best_distance = d;
+            best = i;
+        }
+    }
+    best
+}
+
+/// Octree quantization: every colour starts as a depth 8 leaf and the
+/// least populated sibling groups of the deepest level are folded into their
+/// parent until at most `n` leaves remain. Folding a large group can leave
+/// fewer than `n`.
+pub fn octree(pixels: &[[u8; 3]], n: usize) -> Vec<[u8; 3]> {
+    // Leaf key: (depth, interleaved top `depth` bits of r, g, b).
+    let mut leaves: HashMap<(u32, u32), ([u64; 3], u64)> = HashMap::new();
+    for p in pixels {
+        let leaf = leaves.entry((8, octree_key(p, 8))).or_insert(([0; 3], 0));
+        for (s, &v) in leaf.0.iter_mut().zip(p) {
+            *s += v as u64;
+        }
+        leaf.1 += 1;
+    }
+
+    for depth in (1..=8).rev() {
+        if leaves.len() <= n {
+            break;
+        }
+
+        // Sibling groups at this depth, smallest first.
+        let mut groups: HashMap<u32, u64> = HashMap::new();
+        for (&(d, key), &(_, count)) in &leaves {
+            if d == depth {
+                *groups.entry(key >> 3).or_insert(0) += count;
+            }
+        }
+        let mut groups: Vec<(u32, u64)> = groups.into_iter().collect();
+        groups.sort_by_key(|&(key, count)| (count, key));
+
+        for (parent, _) in groups {
+            if leaves.len() <= n {
+                break;
+            }
+
+            let children: Vec<(u32, u32)> = (0..8).map(|i| (depth, parent << 3 | i))
+                .filter(|k| leaves.contains_key(k))
+                .collect();
+            let mut merged = ([0u64; 3], 0u64);
+            for k in children {
+                let (sum, count) = leaves.remove(&k).unwrap();
+                for (m, s) in merged.0.iter_mut().zip(sum) {
+                    *m += s;
+                }
+                merged.1 += count;
+            }
+
+            let entry = leaves.entry((depth - 1, parent)).or_insert(([0; 3], 0));
+            for (e, m) in entry.0.iter_mut().zip(merged.0) {
+                *e += m;
+            }
+            entry.1 += merged.1;
+        }
+    }
+
+    let mut ranked: Vec<([u64; 3], u64)> = leaves.into_values().collect();
+    ranked.sort_by_key(|&(sum, count)| (std::cmp::Reverse(count), sum));
+    ranked.iter().map(|(sum, count)| sum.map(|s| ((s + count / 2) / count) as u8)).collect()
+}
+
+fn octree_key(p: &[u8; 3], depth: u32) -> u32 {
+    let mut key = 0;
+    for level in 0..depth {
+        let bit = 7 - level;
+        let octant = ((p[0] >> bit) & 1) << 2 | ((p[1] >> bit) & 1) << 1 | ((p[2] >> bit) & 1);
+        key = key << 3 | octant as u32;
+    }
+    key
+}
+
+/// Flat colour and detail samples of `img`, see the module comment.
+pub fn sample_background_foreground(img: &DynamicImage) -> (Vec<[u8; 3]>, Vec<[u8; 3]>) {
+    let (width, height) = img.dimensions();
+    let blocks = ((width / BLOCK).max(1) * (height / BLOCK).max(1)).max(1);
+    // Skip blocks evenly so at most MAX_SAMPLES pixels are read.
+    let step = ((blocks * BLOCK * BLOCK) as f64 / MAX_SAMPLES as f64).ceil().max(1.0) as usize;
+
+    let mut background = Vec::new();
+    let mut foreground = Vec::new();
+
+    let block_origins = (0..height).step_by(BLOCK as usize)
+        .flat_map(|y| (0..width).step_by(BLOCK as usize).map(move |x| (x, y)));
+    for (x, y) in block_origins.step_by(step) {
+        let mut block = Vec::with_capacity((BLOCK * BLOCK) as usize);
+        for py in y..(y + BLOCK).min(height) {
+            for px in x..(x + BLOCK).min(width) {
+                let p = img.get_pixel(px, py).0;
+                block.push([p[0], p[1], p[2]]);
+            }
+        }
+
+        let mean = average(&block);
+        background.push(mean);
+
+        // Pixels further than average from the block mean carry the detail.
+        let distances: Vec<u32> = block.iter()
+            .map(|p| (0..3).map(|c| p[c].abs_diff(mean[c]) as u32).sum())
+            .collect();
+        let threshold = distances.iter().sum::<u32>() / distances.len().max(1) as u32;
+        foreground.extend(block.iter().zip(&distances).filter(|(_, &d)| d > threshold && d > 0).map(|(p, _)| *p));
+    }
+
+    // Flat images have no detail; fall back to their block colours.
+    if foreground.is_empty() {
+        foreground = background.clone();
+    }
+
+    (background, foreground)
+}
+
+/// `count` background and `count` foreground colours for `img`.
+pub fn extract_palette(
+    img: &DynamicImage,
+    count: usize,
+    method: QuantizeMethod,
+    space: ColourSpace,
+) -> (Vec<Rgba<u8>>, Vec<Rgba<u8>>) {
+    let (background, foreground) = sample_background_foreground(img);
+    (quantize(&background, count, method, space), quantize(&foreground, count, method, space))
+}
+
+#[derive(Serialize)]
+struct ExtractedPalette {
+    background: Vec<String>,
+    foreground: Vec<String>,
+}
+
+/// Returns `{ background: [hex...], foreground: [hex...] }` for an encoded
+/// image. `method` is `median-cut`, `k-means` or `octree`, `space` one of
+/// `srgb`, `linear`, `lab` or `oklab` (defaults to the method's usual space).
+#[wasm_bindgen]
+pub fn extract_image_palette(image: Uint8Array, count: u32, method: &str, space: Option<String>) -> Result<JsValue, JsValue> {
+    let method = QuantizeMethod::from_name(method)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown quantize method: {}", method)))?;
+    let space = match space {
+        None => method.default_space(),
+        Some(name) => ColourSpace::from_name(&name)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown colour space: {}", name)))?,
+    };
+    let img = image::load_from_memory(&image.to_vec())
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    let (background, foreground) = extract_palette(&img, count as usize, method, space);
+    let palette = ExtractedPalette {
+        background: background.into_iter().map(format_hex).collect(),
+        foreground: foreground.into_iter().map(format_hex).collect(),
+    };
+
+    serde_wasm_bindgen::to_value(&palette).map_err(JsValue::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: [QuantizeMethod; 3] = [QuantizeMethod::MedianCut, QuantizeMethod::KMeans, QuantizeMethod::Octree];
+    const SPACES: [ColourSpace; 4] = [ColourSpace::Srgb, ColourSpace::Linear, ColourSpace::Lab, ColourSpace::Oklab];
+
+    const RED: [u8; 3] = [200, 30, 40];
+    const TEAL: [u8; 3] = [20, 140, 150];
+
+    /// Three teal pixels to every red one.
+    fn two_colours() -> Vec<[u8; 3]> {
+        (0..400).map(|i| if i % 4 == 0 { RED } else { TEAL }).collect()
+    }
+
+    fn gradient() -> Vec<[u8; 3]> {
+        (0..4096).map(|i| [(i % 16 * 16) as u8, (i / 16 % 16 * 16) as u8, (i / 256 * 16) as u8]).collect()
+    }
+
+    #[test]
+    fn two_colours_quantize_to_themselves() {
+        for method in METHODS {
+            for space in SPACES {
+                let palette = quantize(&two_colours(), 2, method, space);
+                // Most common first.
+                assert_eq!(palette, [Rgba([20, 140, 150, 255]), Rgba([200, 30, 40, 255])], "{:?} {:?}", method, space);
+
+                // Asking for more colours makes none up.
+                let palette = quantize(&two_colours(), 8, method, space);
+                assert!(palette.len() <= 8);
+                assert!(palette.iter().all(|p| [RED, TEAL].contains(&[p[0], p[1], p[2]])), "{:?} {:?} {:?}", method, space, palette);
+            }
+        }
+    }
+
+    #[test]
+    fn respects_k() {
+        let pixels = gradient();
+        for method in METHODS {
+            for k in [1, 2, 5, 16, 64] {
+                let palette = quantize(&pixels, k, method, method.default_space());
+                assert!(!palette.is_empty() && palette.len() <= k, "{:?} {} {}", method, k, palette.len());
+                if method != QuantizeMethod::Octree {
+                    assert_eq!(palette.len(), k, "{:?}", method);
+                }
+            }
+            assert!(quantize(&pixels, 0, method, ColourSpace::Srgb).is_empty());
+            assert!(quantize(&[], 4, method, ColourSpace::Srgb).is_empty());
+        }
+    }
+
+    #[test]
+    fn extracts_two_colour_palettes() {
+        // Red squares on teal.
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |x, y| {
+            let [r, g, b] = if x % 8 < 2 && y % 8 < 2 { RED } else { TEAL };
+            Rgba([r, g, b, 255])
+        }));
+        let (background, foreground) = extract_palette(&img, 1, QuantizeMethod::MedianCut, ColourSpace::Srgb);
+        assert_eq!(foreground, [Rgba([200, 30, 40, 255])]);
+        // The block average, mostly teal.
+        assert_eq!(background.len(), 1);
+        assert!(background[0][2] > background[0][0]);
+    }
+}
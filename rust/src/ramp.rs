@@ -0,0 +1,326 @@
+use image::{DynamicImage, GenericImageView, ImageBuffer, LumaA, Rgba, RgbaImage};
+use js_sys::Uint8Array;
+use rayon::prelude::*;
+use rusttype::{Point, Scale};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::fonts::FontStack;
+use crate::preprocess::{self, luma};
+use crate::FontImageTransformer::{
+    cell_metrics, correct_aspect, coverage_mask, init_thread_pool, layout_glyph, CellMetrics, Coverage, RenderError,
+    RenderOptions,
+};
+
+// Density-ramp rendering.
+//
+// The classic ASCII-art mode: each cell's mean brightness picks a character
+// from a ramp running from the sparsest to the densest glyph, e.g.
+// `" .:-=+*#%@"`. Nothing is matched, so a render costs little more than
+// reading the image, which makes it a quick preview before a full render.
+// With `ramp_auto` the ramp is sorted by the ink each character actually has
+// in the font, and a cell gets the glyph whose ink is closest to its
+// brightness instead of stepping through the ramp evenly.
+//
+// The same measurement is available on its own as [`glyph_densities`], which
+// also splits the ink into quadrants, for building ramps by hand.
+
+type Mask = ImageBuffer<LumaA<f32>, Vec<f32>>;
+
+/// How much ink a character has in the font.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct GlyphDensity {
+    pub character: char,
+    /// Mean coverage over the cell, `0..1`.
+    pub coverage: f32,
+    /// Mean coverage of the top left, top right, bottom left and bottom
+    /// right quarter of the cell.
+    pub quadrants: [f32; 4],
+}
+
+/// The characters from space to `~`.
+pub fn printable_ascii() -> String {
+    (' '..='~').collect()
+}
+
+/// `characters` resolved against the font stack, leaving out those no font
+/// has and repeats of a glyph, and rasterised on a common baseline in a cell
+/// sized for them.
+fn rasterise(fonts: &FontStack, characters: &str, options: &RenderOptions) -> Result<(CellMetrics, Vec<(char, Mask)>), RenderError> {
+    let (resolved, _) = fonts.resolve_charset(characters);
+    if resolved.is_empty() {
+        return Err(RenderError::NoCharacters);
+    }
+
+    let glyphs: Vec<(u8, u16)> = resolved.iter().map(|&(_, glyph)| glyph).collect();
+    let cell = cell_metrics(fonts, &glyphs, options)?;
+    let scales = fonts.scales(Scale { x: options.scale_x, y: options.scale_y });
+    let coverage = Coverage::from_options(options);
+
+    let masks = resolved
+        .into_iter()
+        .map(|(character, (font, i))| {
+            let (scaled_glyph, pen_x, baseline) = layout_glyph(fonts, &scales, &cell, font, i);
+            let glyph = scaled_glyph.positioned(Point { x: pen_x, y: baseline });
+            let (dx, dy) = glyph.pixel_bounding_box().map_or((0, 0), |bb| (bb.min.x, bb.min.y));
+            (character, coverage_mask(&glyph, dx, dy, cell, coverage))
+        })
+        .collect();
+    Ok((cell, masks))
+}
+
+fn density(character: char, mask: &Mask) -> GlyphDensity {
+    let (width, height) = mask.dimensions();
+    let (mut total, mut sums, mut counts) = (0.0, [0.0f32; 4], [0.0f32; 4]);
+    for (x, y, p) in mask.enumerate_pixels() {
+        let quadrant = (y >= height / 2) as usize * 2 + (x >= width / 2) as usize;
+        sums[quadrant] += p[0];
+        counts[quadrant] += 1.0;
+        total += p[0];
+    }
+
+    GlyphDensity {
+        character,
+        coverage: total / (width * height).max(1) as f32,
+        quadrants: [0, 1, 2, 3].map(|q| sums[q] / counts[q].max(1.0)),
+    }
+}
+
+/// Rasterises `charset` from the font stack at the size `options` sets and
+/// returns each character's ink, sparsest first.
+pub fn glyph_densities(fonts: &FontStack, charset: &str, options: &RenderOptions) -> Result<Vec<GlyphDensity>, RenderError> {
+    let (_, masks) = rasterise(fonts, charset, options)?;
+    let mut densities: Vec<GlyphDensity> = masks.iter().map(|(character, mask)| density(*character, mask)).collect();
+    densities.sort_by(|a, b| a.coverage.total_cmp(&b.coverage));
+    Ok(densities)
+}
+
+/// [`glyph_densities`] of `charset` (printable ASCII when empty) in `font`,
+/// as an array of `{ character, coverage, quadrants }`. `options` is a
+/// (partial) `RenderOptions` object for the size and coverage settings.
+#[wasm_bindgen]
+pub fn glyph_density(font: Uint8Array, charset: &str, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: RenderOptions = match options.is_undefined() || options.is_null() {
+        true => RenderOptions::default(),
+        false => serde_wasm_bindgen::from_value(options)?,
+    };
+    let charset = match charset {
+        "" => printable_ascii(),
+        charset => charset.to_string(),
+    };
+
+    let fonts = FontStack::new(vec![font.to_vec()]).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let densities = glyph_densities(&fonts, &charset, &options).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    serde_wasm_bindgen::to_value(&densities).map_err(JsValue::from)
+}
+
+/// One character of a ramp, drawn in the ramp's cell.
+pub struct RampGlyph {
+    pub character: char,
+    /// Coverage as [`coverage_mask`] returns it.
+    pub mask: Mask,
+    /// Mean coverage over the cell, `0..1`.
+    pub ink: f32,
+    /// Brightness (`0..1`) the glyph stands for.
+    pub level: f32,
+}
+
+pub struct Ramp {
+    pub cell: CellMetrics,
+    /// Sparsest first.
+    pub glyphs: Vec<RampGlyph>,
+}
+
+impl Ramp {
+    /// Rasterises `characters` (sparsest first) from the font stack,
+    /// re-ordered by their ink when `options.ramp_auto` is set. Characters
+    /// no font has are left out.
+    pub fn new(fonts: &FontStack, characters: &str, options: &RenderOptions) -> Result<Ramp, RenderError> {
+        let (cell, masks) = rasterise(fonts, characters, options)?;
+        let mut glyphs: Vec<RampGlyph> = masks
+            .into_iter()
+            .map(|(character, mask)| {
+                let ink = density(character, &mask).coverage;
+                RampGlyph { character, mask, ink, level: 0.0 }
+            })
+            .collect();
+
+        let last = (glyphs.len() - 1).max(1) as f32;
+        match options.ramp_auto {
+            false => {
+                for (k, glyph) in glyphs.iter_mut().enumerate() {
+                    glyph.level = k as f32 / last;
+                }
+            }
+            true => {
+                glyphs.sort_by(|a, b| a.ink.total_cmp(&b.ink));
+                let (min, max) = (glyphs[0].ink, glyphs[glyphs.len() - 1].ink);
+                for (k, glyph) in glyphs.iter_mut().enumerate() {
+                    glyph.level = match max > min {
+                        true => (glyph.ink - min) / (max - min),
+                        false => k as f32 / last,
+                    };
+                }
+            }
+        }
+
+        Ok(Ramp { cell, glyphs })
+    }
+
+    /// The glyph standing for `brightness` (`0..1`).
+    pub fn pick(&self, brightness: f32) -> &RampGlyph {
+        self.glyphs
+            .iter()
+            .min_by(|a, b| (a.level - brightness).abs().total_cmp(&(b.level - brightness).abs()))
+            .unwrap()
+    }
+
+    /// The characters, sparsest first.
+    pub fn characters(&self) -> String {
+        self.glyphs.iter().map(|glyph| glyph.character).collect()
+    }
+}
+
+/// Renders `img` with the ramp `characters`. `progress` is called with
+/// (completed rows, total rows).
+pub fn render_ramp(
+    fonts: &FontStack,
+    img: &DynamicImage,
+    characters: &str,
+    options: &RenderOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> Result<RgbaImage, RenderError> {
+    init_thread_pool();
+
+    let ramp = Ramp::new(fonts, characters, options)?;
+    let (kx, ky) = (ramp.cell.width, ramp.cell.height);
+    let coverage = Coverage::from_options(options);
+
+    let preprocessed;
+    let img = match options.preprocess.as_slice() {
+        [] => img,
+        stages => {
+            preprocessed = preprocess::apply(img, stages);
+            &preprocessed
+        }
+    };
+
+    let corrected;
+    let img = match options.aspect_correction {
+        false => img,
+        true => {
+            let display = options.cell_aspect.unwrap_or_else(|| ramp.cell.aspect());
+            corrected = correct_aspect(img, kx, ky, display);
+            &corrected
+        }
+    };
+
+    // Light glyphs on black, or dark glyphs on white with `ramp_invert`.
+    let (paper, ink) = match options.ramp_invert {
+        false => (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255])),
+        true => (Rgba([255, 255, 255, 255]), Rgba([0, 0, 0, 255])),
+    };
+
+    let (width, height) = img.dimensions();
+    let mut out: RgbaImage = ImageBuffer::new(width, height);
+    let rows: Vec<u32> = (0..height / ky).map(|row| row * ky).collect();
+    let columns: Vec<u32> = (0..width / kx).map(|column| column * kx).collect();
+    let total_rows = rows.len() as u32;
+
+    for (row, &y) in rows.iter().enumerate() {
+        let cells: Vec<Option<(&RampGlyph, Rgba<u8>, u32)>> = columns
+            .par_iter()
+            .map(|&x| {
+                let mut sum = [0.0f32; 4];
+                for p in img.view(x, y, kx, ky).pixels().map(|(_, _, p)| p) {
+                    for c in 0..4 {
+                        sum[c] += p[c] as f32;
+                    }
+                }
+                let mean = sum.map(|s| (s / (kx * ky) as f32).round() as u8);
+                if options.transparency && (mean[3] as f32) < options.alpha_threshold * 255.0 {
+                    return None;
+                }
+
+                let brightness = luma(&Rgba(mean)) / 255.0;
+                let glyph = ramp.pick(match options.ramp_invert {
+                    false => brightness,
+                    true => 1.0 - brightness,
+                });
+                let colour = match options.ramp_colour {
+                    true => Rgba([mean[0], mean[1], mean[2], 255]),
+                    false => ink,
+                };
+                Some((glyph, colour, x))
+            })
+            .collect();
+
+        // Transparent cells stay empty.
+        for (glyph, colour, x) in cells.into_iter().flatten() {
+            for (gx, gy, m) in glyph.mask.enumerate_pixels() {
+                let pixel = out.get_pixel_mut(x + gx, y + gy);
+                *pixel = paper;
+                coverage.blend(pixel, colour, m[0]);
+            }
+        }
+
+        progress(row as u32 + 1, total_rows);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fonts() -> FontStack {
+        FontStack::new(vec![include_bytes!("../fonts/Arial-Monospaced.ttf").to_vec()]).unwrap()
+    }
+
+    #[test]
+    fn even_levels() {
+        let ramp = Ramp::new(&fonts(), " .:#", &RenderOptions::default()).unwrap();
+        let levels: Vec<f32> = ramp.glyphs.iter().map(|glyph| glyph.level).collect();
+        assert_eq!(levels, [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]);
+        assert_eq!(ramp.pick(0.0).character, ' ');
+        assert_eq!(ramp.pick(0.4).character, '.');
+        assert_eq!(ramp.pick(1.0).character, '#');
+    }
+
+    #[test]
+    fn auto_orders_by_ink() {
+        let options = RenderOptions { ramp_auto: true, ..RenderOptions::default() };
+        let ramp = Ramp::new(&fonts(), "#. :", &options).unwrap();
+        assert_eq!(ramp.characters(), " .:#");
+        assert!(ramp.glyphs.windows(2).all(|pair| pair[0].ink <= pair[1].ink));
+        assert_eq!(ramp.glyphs[0].level, 0.0);
+        assert_eq!(ramp.glyphs[3].level, 1.0);
+    }
+
+    #[test]
+    fn invert_swaps_paper() {
+        let white = DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 64, Rgba([255, 255, 255, 255])));
+        let options = RenderOptions { ramp_invert: true, ..RenderOptions::default() };
+        // White stands for the sparsest glyph, drawn on white paper.
+        let out = render_ramp(&fonts(), &white, " .:#", &options, &mut |_, _| {}).unwrap();
+        let ramp = Ramp::new(&fonts(), " .:#", &options).unwrap();
+        let (width, height) = (64 / ramp.cell.width * ramp.cell.width, 64 / ramp.cell.height * ramp.cell.height);
+        assert!(width > 0 && height > 0);
+        assert!(out.view(0, 0, width, height).pixels().all(|(_, _, p)| p == Rgba([255, 255, 255, 255])));
+    }
+
+    #[test]
+    fn densities_sorted_with_quadrants() {
+        let densities = glyph_densities(&fonts(), "_#' ", &RenderOptions::default()).unwrap();
+        assert_eq!(densities.iter().map(|d| d.character).collect::<String>(), " '_#");
+        assert!(densities.windows(2).all(|pair| pair[0].coverage <= pair[1].coverage));
+        assert_eq!(densities[0].coverage, 0.0);
+
+        // The underscore sits at the bottom, the apostrophe at the top.
+        let [tl, tr, bl, br] = densities[2].quadrants;
+        assert!(bl + br > 0.0 && tl + tr == 0.0);
+        let [tl, tr, bl, br] = densities[1].quadrants;
+        assert!(tl + tr > 0.0 && bl + br == 0.0);
+    }
+}
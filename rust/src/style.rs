@@ -0,0 +1,380 @@
+use ab_glyph_rasterizer::{point as ab_point, Rasterizer};
+use rusttype::{point, OutlineBuilder, Point, PositionedGlyph, Rect, ScaledGlyph};
+use serde::{Deserialize, Serialize};
+
+// Glyph styles.
+//
+// The atlas can hold every glyph in several styles so the matcher can pick
+// heavier strokes for dark regions. A style comes from its own font file when
+// one is loaded into the font stack (a real bold or italic cut), otherwise it
+// is synthesised from the regular outline: bold by pushing every outline
+// point outwards along the contour's normals (the same dilation FreeType's
+// emboldening does), italic by shearing the outline. Each render records its
+// style so text writers can emit the matching bold / italic attributes.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GlyphStyle {
+    #[default]
+    Regular,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl GlyphStyle {
+    pub fn from_name(name: &str) -> Option<GlyphStyle> {
+        match name {
+            "regular" => Some(GlyphStyle::Regular),
+            "bold" => Some(GlyphStyle::Bold),
+            "italic" => Some(GlyphStyle::Italic),
+            "bold-italic" => Some(GlyphStyle::BoldItalic),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GlyphStyle::Regular => "regular",
+            GlyphStyle::Bold => "bold",
+            GlyphStyle::Italic => "italic",
+            GlyphStyle::BoldItalic => "bold-italic",
+        }
+    }
+
+    pub fn is_bold(self) -> bool {
+        matches!(self, GlyphStyle::Bold | GlyphStyle::BoldItalic)
+    }
+
+    pub fn is_italic(self) -> bool {
+        matches!(self, GlyphStyle::Italic | GlyphStyle::BoldItalic)
+    }
+}
+
+/// Something that rasterises into a cell like a `PositionedGlyph`: `draw`
+/// calls `o(x, y, coverage)` for every pixel of `pixel_bounding_box`, with
+/// coordinates relative to its `min`.
+pub trait Rasterise {
+    fn pixel_bounding_box(&self) -> Option<Rect<i32>>;
+    fn draw(&self, o: &mut dyn FnMut(u32, u32, f32));
+}
+
+impl Rasterise for PositionedGlyph<'_> {
+    fn pixel_bounding_box(&self) -> Option<Rect<i32>> {
+        PositionedGlyph::pixel_bounding_box(self)
+    }
+
+    fn draw(&self, o: &mut dyn FnMut(u32, u32, f32)) {
+        PositionedGlyph::draw(self, o);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Segment {
+    Line(Point<f32>),
+    Quad(Point<f32>, Point<f32>),
+    Cubic(Point<f32>, Point<f32>, Point<f32>),
+}
+
+impl Segment {
+    fn points(&self) -> Vec<Point<f32>> {
+        match *self {
+            Segment::Line(a) => vec![a],
+            Segment::Quad(a, b) => vec![a, b],
+            Segment::Cubic(a, b, c) => vec![a, b, c],
+        }
+    }
+
+    fn with_points(&self, points: &[Point<f32>]) -> Segment {
+        match self {
+            Segment::Line(_) => Segment::Line(points[0]),
+            Segment::Quad(..) => Segment::Quad(points[0], points[1]),
+            Segment::Cubic(..) => Segment::Cubic(points[0], points[1], points[2]),
+        }
+    }
+}
+
+/// A closed contour: its start and the segments after it.
+#[derive(Clone, Debug, Default)]
+struct Contour {
+    start: Point<f32>,
+    segments: Vec<Segment>,
+}
+
+#[derive(Default)]
+struct Recorder {
+    contours: Vec<Contour>,
+}
+
+impl OutlineBuilder for Recorder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.contours.push(Contour { start: point(x, y), segments: Vec::new() });
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        if let Some(contour) = self.contours.last_mut() {
+            contour.segments.push(Segment::Line(point(x, y)));
+        }
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        if let Some(contour) = self.contours.last_mut() {
+            contour.segments.push(Segment::Quad(point(x1, y1), point(x, y)));
+        }
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        if let Some(contour) = self.contours.last_mut() {
+            contour.segments.push(Segment::Cubic(point(x1, y1), point(x2, y2), point(x, y)));
+        }
+    }
+
+    fn close(&mut self) {}
+}
+
+/// A glyph outline emboldened and / or sheared, placed in a cell.
+pub struct SyntheticGlyph {
+    contours: Vec<Contour>,
+    bounds: Option<Rect<i32>>,
+}
+
+impl SyntheticGlyph {
+    /// `glyph` with its origin at `position`, every stroke `embolden` pixels
+    /// wider and sheared by `skew` (the tangent of the slant) around the row
+    /// `pivot_y`.
+    pub fn new(glyph: &ScaledGlyph, position: Point<f32>, embolden: f32, skew: f32, pivot_y: f32) -> SyntheticGlyph {
+        let mut recorder = Recorder::default();
+        glyph.build_outline(&mut recorder);
+        let mut contours = recorder.contours;
+
+        if embolden > 0.0 {
+            let outward = match signed_area(&contours) >= 0.0 {
+                true => 1.0,
+                false => -1.0,
+            };
+            for contour in &mut contours {
+                dilate(contour, embolden / 2.0 * outward);
+            }
+        }
+
+        let place = |p: Point<f32>| {
+            let (x, y) = (p.x + position.x, p.y + position.y);
+            point(x - skew * (y - pivot_y), y)
+        };
+        for contour in &mut contours {
+            contour.start = place(contour.start);
+            for segment in &mut contour.segments {
+                let points: Vec<Point<f32>> = segment.points().into_iter().map(place).collect();
+                *segment = segment.with_points(&points);
+            }
+        }
+
+        // Control points bound the curves.
+        let all = contours.iter().flat_map(|c| std::iter::once(c.start).chain(c.segments.iter().flat_map(Segment::points)));
+        let bounds = all.fold(None, |bounds: Option<Rect<f32>>, p| match bounds {
+            None => Some(Rect { min: p, max: p }),
+            Some(r) => Some(Rect {
+                min: point(r.min.x.min(p.x), r.min.y.min(p.y)),
+                max: point(r.max.x.max(p.x), r.max.y.max(p.y)),
+            }),
+        });
+        let bounds = bounds.map(|r| Rect {
+            min: point(r.min.x.floor() as i32, r.min.y.floor() as i32),
+            max: point(r.max.x.ceil() as i32, r.max.y.ceil() as i32),
+        });
+
+        SyntheticGlyph { contours, bounds }
+    }
+}
+
+impl Rasterise for SyntheticGlyph {
+    fn pixel_bounding_box(&self) -> Option<Rect<i32>> {
+        self.bounds
+    }
+
+    fn draw(&self, o: &mut dyn FnMut(u32, u32, f32)) {
+        let Some(bb) = self.bounds else {
+            return;
+        };
+        let (width, height) = ((bb.max.x - bb.min.x) as usize, (bb.max.y - bb.min.y) as usize);
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let local = |p: Point<f32>| ab_point(p.x - bb.min.x as f32, p.y - bb.min.y as f32);
+        let mut rasterizer = Rasterizer::new(width, height);
+        for contour in &self.contours {
+            let mut last = contour.start;
+            for segment in &contour.segments {
+                last = match *segment {
+                    Segment::Line(a) => {
+                        rasterizer.draw_line(local(last), local(a));
+                        a
+                    }
+                    Segment::Quad(a, b) => {
+                        rasterizer.draw_quad(local(last), local(a), local(b));
+                        b
+                    }
+                    Segment::Cubic(a, b, c) => {
+                        rasterizer.draw_cubic(local(last), local(a), local(b), local(c));
+                        c
+                    }
+                };
+            }
+            if last != contour.start {
+                rasterizer.draw_line(local(last), local(contour.start));
+            }
+        }
+
+        rasterizer.for_each_pixel_2d(o);
+    }
+}
+
+/// Shoelace area of all contours over every point, control points included.
+/// Its sign tells which side of the contours is outside.
+fn signed_area(contours: &[Contour]) -> f32 {
+    contours.iter()
+        .map(|contour| {
+            let points = contour_points(contour);
+            (0..points.len())
+                .map(|i| {
+                    let (a, b) = (points[i], points[(i + 1) % points.len()]);
+                    a.x * b.y - b.x * a.y
+                })
+                .sum::<f32>()
+        })
+        .sum()
+}
+
+fn contour_points(contour: &Contour) -> Vec<Point<f32>> {
+    let mut points = vec![contour.start];
+    points.extend(contour.segments.iter().flat_map(Segment::points));
+    // The closing point usually repeats the start.
+    if points.len() > 1 && points.last() == Some(&contour.start) {
+        points.pop();
+    }
+    points
+}
+
+/// Moves every point of `contour` by `distance` along the mitred normal of
+/// its two edges (outwards for positive `distance` on positive contours).
+fn dilate(contour: &mut Contour, distance: f32) {
+    let points = contour_points(contour);
+    let n = points.len();
+    if n < 3 {
+        return;
+    }
+
+    let normal = |a: Point<f32>, b: Point<f32>| {
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let length = (dx * dx + dy * dy).sqrt();
+        (length > 1e-6).then(|| (dy / length, -dx / length))
+    };
+    // The nearest distinct neighbour in direction `step`.
+    let neighbour = |i: usize, step: usize| {
+        (1..n).map(|k| points[(i + k * step) % n]).find(|&p| p != points[i])
+    };
+
+    let shifted: Vec<Point<f32>> = (0..n)
+        .map(|i| {
+            let p = points[i];
+            let (Some(prev), Some(next)) = (neighbour(i, n - 1), neighbour(i, 1)) else {
+                return p;
+            };
+            let (Some(a), Some(b)) = (normal(prev, p), normal(p, next)) else {
+                return p;
+            };
+
+            // Mitre, capped so near reversals don't spike.
+            let d = (1.0 + a.0 * b.0 + a.1 * b.1).max(0.5);
+            point(p.x + (a.0 + b.0) / d * distance, p.y + (a.1 + b.1) / d * distance)
+        })
+        .collect();
+
+    // A closing point that repeated the start wraps round to it again.
+    contour.start = shifted[0];
+    let mut next = 1;
+    for segment in &mut contour.segments {
+        let len = segment.points().len();
+        let moved: Vec<Point<f32>> = (next..next + len).map(|j| shifted[j % n]).collect();
+        *segment = segment.with_points(&moved);
+        next += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusttype::{Font, Scale};
+
+    const SIZE: u32 = 64;
+    const BASELINE: f32 = 48.0;
+
+    fn font() -> Font<'static> {
+        Font::try_from_bytes(include_bytes!("../fonts/Arial-Monospaced.ttf")).unwrap()
+    }
+
+    /// Coverage of `glyph` over a `SIZE` x `SIZE` cell.
+    fn raster(glyph: &dyn Rasterise) -> Vec<f32> {
+        let mut cell = vec![0.0; (SIZE * SIZE) as usize];
+        let bb = glyph.pixel_bounding_box().unwrap();
+        glyph.draw(&mut |x, y, v| {
+            let (x, y) = (x as i32 + bb.min.x, y as i32 + bb.min.y);
+            if (0..SIZE as i32).contains(&x) && (0..SIZE as i32).contains(&y) {
+                cell[(y as u32 * SIZE + x as u32) as usize] = v;
+            }
+        });
+        cell
+    }
+
+    /// Mean ink column of row `y`, `None` for an empty row.
+    fn centre(cell: &[f32], y: u32) -> Option<f32> {
+        let row = &cell[(y * SIZE) as usize..((y + 1) * SIZE) as usize];
+        let ink: f32 = row.iter().sum();
+        (ink > 0.5).then(|| row.iter().enumerate().map(|(x, v)| x as f32 * v).sum::<f32>() / ink)
+    }
+
+    #[test]
+    fn plain_outline_matches_rusttype() {
+        let font = font();
+        let glyph = font.glyph('a').scaled(Scale::uniform(40.0));
+        let position = point(12.0, BASELINE);
+        let regular = raster(&glyph.clone().positioned(position));
+        let synthetic = raster(&SyntheticGlyph::new(&glyph, position, 0.0, 0.0, BASELINE));
+        assert!(regular.iter().zip(&synthetic).all(|(a, b)| (a - b).abs() < 0.02));
+    }
+
+    #[test]
+    fn bold_only_adds_ink() {
+        let font = font();
+        for c in ['a', 'o', 'H', '/'] {
+            let glyph = font.glyph(c).scaled(Scale::uniform(40.0));
+            let position = point(12.0, BASELINE);
+            let regular = raster(&glyph.clone().positioned(position));
+            let bold = raster(&SyntheticGlyph::new(&glyph, position, 2.0, 0.0, BASELINE));
+
+            assert!(regular.iter().zip(&bold).all(|(r, b)| b + 0.02 >= *r), "{:?} lost ink", c);
+            let (ink, bold_ink) = (regular.iter().sum::<f32>(), bold.iter().sum::<f32>());
+            assert!(bold_ink > ink * 1.2, "{:?}: {} vs {}", c, bold_ink, ink);
+        }
+    }
+
+    #[test]
+    fn italic_shears_above_the_baseline() {
+        let font = font();
+        let glyph = font.glyph('|').scaled(Scale::uniform(40.0));
+        let position = point(24.0, BASELINE);
+        let skew = 0.25;
+        let regular = raster(&glyph.clone().positioned(position));
+        let italic = raster(&SyntheticGlyph::new(&glyph, position, 0.0, skew, BASELINE));
+
+        // The row above the baseline stays put, rows higher up move right by
+        // `skew` per row.
+        let shift = |y: u32| centre(&italic, y).unwrap() - centre(&regular, y).unwrap();
+        let expected = |y: u32| skew * (BASELINE - (y as f32 + 0.5));
+        let top = (0..BASELINE as u32).find(|&y| centre(&regular, y).is_some()).unwrap();
+        for y in [BASELINE as u32 - 1, top + 2, top + 10] {
+            assert!((shift(y) - expected(y)).abs() < 0.5, "row {}: {} vs {}", y, shift(y), expected(y));
+        }
+    }
+}
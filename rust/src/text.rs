@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use image::Rgba;
+
+use crate::atlas::GlyphAtlas;
+use crate::blocks::{BlockCell, BlockMode};
+use crate::palette::format_hex;
+use crate::style::GlyphStyle;
+
+// Text output of renders.
+//
+// Block, Braille and glyph renders are made of characters, so besides being
+// painted they can be written out for a terminal, a text file or a web page.
+// Plain text is just the characters, one line per row of cells. ANSI text
+// also sets each cell's foreground and background with 24-bit colour
+// escapes and its style with the bold and italic attributes, only where they
+// change. HTML wraps runs of equally drawn cells in styled spans inside a
+// `<pre>`. Transparent colours (Braille backgrounds, empty cells, coverage
+// mask backgrounds) leave the terminal's or page's own colour.
+
+const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+/// One character of text output and how it is drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextCell {
+    pub character: char,
+    pub foreground: Rgba<u8>,
+    pub background: Rgba<u8>,
+    pub style: GlyphStyle,
+}
+
+impl TextCell {
+    /// A transparent cell, written as a space.
+    pub const EMPTY: TextCell = TextCell {
+        character: ' ',
+        foreground: TRANSPARENT,
+        background: TRANSPARENT,
+        style: GlyphStyle::Regular,
+    };
+}
+
+/// The cells of a block render, see [`crate::blocks::render_blocks`].
+pub fn block_text(cells: &[Vec<BlockCell>], mode: BlockMode) -> Vec<Vec<TextCell>> {
+    cells.iter()
+        .map(|row| row.iter()
+            .map(|cell| TextCell {
+                character: mode.character(cell.pattern),
+                foreground: cell.foreground,
+                background: cell.background,
+                style: GlyphStyle::Regular,
+            })
+            .collect())
+        .collect()
+}
+
+/// The cells of a glyph render, from the renders of `atlas` that
+/// [`crate::FontImageTransformer::render_with_atlas`] picked. `codepoints`
+/// are the stack's [`crate::fonts::FontStack::codepoints`]; glyphs without
+/// one are written as U+FFFD. Rotated and mirrored variants are written as
+/// the character they were made from.
+pub fn atlas_text(matches: &[Vec<Option<usize>>], atlas: &GlyphAtlas, codepoints: &[HashMap<u16, char>]) -> Vec<Vec<TextCell>> {
+    matches.iter()
+        .map(|row| row.iter()
+            .map(|&index| {
+                let Some(render) = index.map(|index| &atlas.renders[index]) else {
+                    return TextCell::EMPTY;
+                };
+                let character = codepoints.get(render.font as usize)
+                    .and_then(|map| map.get(&render.glyph))
+                    .copied()
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                // Coverage masks are white on transparent.
+                TextCell {
+                    character,
+                    foreground: Rgba(render.foreground.unwrap_or([255; 4])),
+                    background: render.background.map_or(TRANSPARENT, Rgba),
+                    style: render.style,
+                }
+            })
+            .collect())
+        .collect()
+}
+
+/// The characters of `cells`, one line per row.
+pub fn plain_text(cells: &[Vec<TextCell>]) -> String {
+    let mut text = String::new();
+    for row in cells {
+        text.extend(row.iter().map(|cell| cell.character));
+        text.push('\n');
+    }
+    text
+}
+
+/// `None` for a transparent colour.
+fn opaque(colour: Rgba<u8>) -> Option<[u8; 3]> {
+    (colour[3] > 0).then_some([colour[0], colour[1], colour[2]])
+}
+
+/// The characters of `cells` in their colours and styles, as ANSI escape
+/// sequences. Every line ends with the attributes reset.
+pub fn ansi_text(cells: &[Vec<TextCell>]) -> String {
+    let mut text = String::new();
+    for row in cells {
+        let (mut foreground, mut background, mut style) = (None, None, GlyphStyle::Regular);
+        for cell in row {
+            // Spaces show no foreground, so it is left as it is.
+            if cell.character != ' ' && opaque(cell.foreground) != foreground {
+                foreground = opaque(cell.foreground);
+                match foreground {
+                    Some([r, g, b]) => write!(text, "\x1b[38;2;{};{};{}m", r, g, b).unwrap(),
+                    None => text.push_str("\x1b[39m"),
+                }
+            }
+            if opaque(cell.background) != background {
+                background = opaque(cell.background);
+                match background {
+                    Some([r, g, b]) => write!(text, "\x1b[48;2;{};{};{}m", r, g, b).unwrap(),
+                    None => text.push_str("\x1b[49m"),
+                }
+            }
+            if cell.style.is_bold() != style.is_bold() {
+                text.push_str(if cell.style.is_bold() { "\x1b[1m" } else { "\x1b[22m" });
+            }
+            if cell.style.is_italic() != style.is_italic() {
+                text.push_str(if cell.style.is_italic() { "\x1b[3m" } else { "\x1b[23m" });
+            }
+            style = cell.style;
+            text.push(cell.character);
+        }
+        if foreground.is_some() || background.is_some() || style != GlyphStyle::Regular {
+            text.push_str("\x1b[0m");
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// The CSS drawing `cell`, empty if it needs none.
+fn css(cell: &TextCell) -> String {
+    let mut css = Vec::new();
+    if cell.foreground[3] > 0 {
+        css.push(format!("color:{}", format_hex(cell.foreground)));
+    }
+    if cell.background[3] > 0 {
+        css.push(format!("background-color:{}", format_hex(cell.background)));
+    }
+    if cell.style.is_bold() {
+        css.push("font-weight:bold".to_string());
+    }
+    if cell.style.is_italic() {
+        css.push("font-style:italic".to_string());
+    }
+    css.join(";")
+}
+
+/// `cells` as an HTML `<pre>` block, each run of equally drawn cells in a
+/// `<span>` styled with its colours, weight and slant.
+pub fn html_text(cells: &[Vec<TextCell>]) -> String {
+    let mut html = String::from("<pre>");
+    for row in cells {
+        let mut cells = row.iter().peekable();
+        while let Some(cell) = cells.next() {
+            let style = css(cell);
+            if !style.is_empty() {
+                write!(html, "<span style=\"{}\">", style).unwrap();
+            }
+            let mut run = vec![cell.character];
+            while let Some(next) = cells.next_if(|next| css(next) == style) {
+                run.push(next.character);
+            }
+            for c in run {
+                match c {
+                    '&' => html.push_str("&amp;"),
+                    '<' => html.push_str("&lt;"),
+                    '>' => html.push_str("&gt;"),
+                    c => html.push(c),
+                }
+            }
+            if !style.is_empty() {
+                html.push_str("</span>");
+            }
+        }
+        html.push('\n');
+    }
+    html.push_str("</pre>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    fn cells() -> Vec<Vec<TextCell>> {
+        block_text(&[
+            vec![
+                BlockCell { pattern: 1, foreground: RED, background: BLUE },
+                BlockCell { pattern: 2, foreground: RED, background: BLUE },
+                BlockCell { pattern: 0, foreground: BLUE, background: BLUE },
+            ],
+            vec![BlockCell::EMPTY, BlockCell { pattern: 3, foreground: BLUE, background: BLUE }],
+        ], BlockMode::Half)
+    }
+
+    fn styled() -> Vec<Vec<TextCell>> {
+        let cell = |character, style| TextCell { character, foreground: RED, background: TRANSPARENT, style };
+        vec![vec![
+            cell('a', GlyphStyle::Regular),
+            cell('<', GlyphStyle::Bold),
+            cell('c', GlyphStyle::BoldItalic),
+            cell('d', GlyphStyle::Italic),
+            TextCell::EMPTY,
+        ]]
+    }
+
+    #[test]
+    fn atlas_characters() {
+        use crate::fonts::FontStack;
+        use crate::FontImageTransformer::RenderOptions;
+
+        let fonts = FontStack::new(vec![include_bytes!("../fonts/Arial-Monospaced.ttf").to_vec()]).unwrap();
+        let options = RenderOptions {
+            charset: Some("ab".to_string()),
+            glyph_styles: vec![GlyphStyle::Bold],
+            ..RenderOptions::default()
+        };
+        let atlas = GlyphAtlas::build(String::new(), &fonts, &[BLUE], &[RED], &options).unwrap();
+        let find = |c, style: GlyphStyle| atlas.renders.iter()
+            .position(|r| fonts.codepoints()[r.font as usize].get(&r.glyph) == Some(&c) && r.style == style && r.background.is_some())
+            .unwrap();
+
+        let matches = [vec![Some(find('a', GlyphStyle::Regular)), None, Some(find('b', GlyphStyle::Bold))]];
+        let cells = atlas_text(&matches, &atlas, &fonts.codepoints());
+        assert_eq!(cells[0], [
+            TextCell { character: 'a', foreground: RED, background: BLUE, style: GlyphStyle::Regular },
+            TextCell::EMPTY,
+            TextCell { character: 'b', foreground: RED, background: BLUE, style: GlyphStyle::Bold },
+        ]);
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(plain_text(&cells()), "▀▄ \n █\n");
+    }
+
+    #[test]
+    fn ansi() {
+        assert_eq!(
+            ansi_text(&cells()),
+            concat!(
+                "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m▀▄ \x1b[0m\n",
+                " \x1b[38;2;0;0;255m\x1b[48;2;0;0;255m█\x1b[0m\n",
+            )
+        );
+        assert_eq!(
+            ansi_text(&styled()),
+            "\x1b[38;2;255;0;0ma\x1b[1m<\x1b[3mc\x1b[22md\x1b[23m \x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            html_text(&styled()),
+            concat!(
+                "<pre><span style=\"color:#ff0000\">a</span>",
+                "<span style=\"color:#ff0000;font-weight:bold\">&lt;</span>",
+                "<span style=\"color:#ff0000;font-weight:bold;font-style:italic\">c</span>",
+                "<span style=\"color:#ff0000;font-style:italic\">d</span> \n</pre>\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use image::{imageops, ImageBuffer, Pixel};
+use serde::{Deserialize, Serialize};
+
+// Glyph variants.
+//
+// For mosaics where the text doesn't need to stay readable, every glyph can
+// also be offered rotated or mirrored, which gives the matcher more shapes
+// for the same font. Quarter turns of a cell that isn't square are stretched
+// back to the cell size. Nearest neighbour keeps each render pixel a copy of
+// one source pixel, so variants compare exactly like their coverage masks.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GlyphVariant {
+    #[serde(rename = "rotate-90")]
+    Rotate90,
+    #[serde(rename = "rotate-180")]
+    Rotate180,
+    #[serde(rename = "rotate-270")]
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+}
+
+impl GlyphVariant {
+    pub fn name(self) -> &'static str {
+        match self {
+            GlyphVariant::Rotate90 => "rotate-90",
+            GlyphVariant::Rotate180 => "rotate-180",
+            GlyphVariant::Rotate270 => "rotate-270",
+            GlyphVariant::FlipHorizontal => "flip-horizontal",
+            GlyphVariant::FlipVertical => "flip-vertical",
+        }
+    }
+
+    /// `img` turned or mirrored, at its original size.
+    pub fn apply<P>(self, img: &ImageBuffer<P, Vec<P::Subpixel>>) -> ImageBuffer<P, Vec<P::Subpixel>>
+    where
+        P: Pixel + 'static,
+    {
+        let (width, height) = img.dimensions();
+        let out = match self {
+            GlyphVariant::Rotate90 => imageops::rotate90(img),
+            GlyphVariant::Rotate180 => imageops::rotate180(img),
+            GlyphVariant::Rotate270 => imageops::rotate270(img),
+            GlyphVariant::FlipHorizontal => imageops::flip_horizontal(img),
+            GlyphVariant::FlipVertical => imageops::flip_vertical(img),
+        };
+
+        match out.dimensions() == (width, height) {
+            true => out,
+            false => imageops::resize(&out, width, height, imageops::FilterType::Nearest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    const VARIANTS: [GlyphVariant; 5] = [
+        GlyphVariant::Rotate90,
+        GlyphVariant::Rotate180,
+        GlyphVariant::Rotate270,
+        GlyphVariant::FlipHorizontal,
+        GlyphVariant::FlipVertical,
+    ];
+
+    /// Rows of `img`.
+    fn rows(img: &GrayImage) -> Vec<Vec<u8>> {
+        img.rows().map(|row| row.map(|p| p[0]).collect()).collect()
+    }
+
+    fn image(rows: &[&[u8]]) -> GrayImage {
+        GrayImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| Luma([rows[y as usize][x as usize]]))
+    }
+
+    #[test]
+    fn square_cells() {
+        let img = image(&[&[1, 2], &[3, 4]]);
+        let applied = |variant: GlyphVariant| rows(&variant.apply(&img));
+        // Clockwise turns.
+        assert_eq!(applied(GlyphVariant::Rotate90), [[3, 1], [4, 2]]);
+        assert_eq!(applied(GlyphVariant::Rotate180), [[4, 3], [2, 1]]);
+        assert_eq!(applied(GlyphVariant::Rotate270), [[2, 4], [1, 3]]);
+        assert_eq!(applied(GlyphVariant::FlipHorizontal), [[2, 1], [4, 3]]);
+        assert_eq!(applied(GlyphVariant::FlipVertical), [[3, 4], [1, 2]]);
+
+        let twice = |variant: GlyphVariant| rows(&variant.apply(&variant.apply(&img)));
+        assert_eq!(twice(GlyphVariant::Rotate90), applied(GlyphVariant::Rotate180));
+        assert_eq!(twice(GlyphVariant::FlipHorizontal), rows(&img));
+        assert_eq!(twice(GlyphVariant::FlipVertical), rows(&img));
+    }
+
+    #[test]
+    fn tall_cells_keep_their_size() {
+        // A 2 x 4 cell: quarter turns are stretched back with nearest
+        // neighbour, so every pixel is still one of the source's.
+        let img = image(&[&[1, 2], &[3, 4], &[5, 6], &[7, 8]]);
+        for variant in VARIANTS {
+            let out = variant.apply(&img);
+            assert_eq!(out.dimensions(), (2, 4), "{:?}", variant);
+            assert!(out.pixels().all(|p| (1..=8).contains(&p[0])), "{:?}", variant);
+        }
+        assert_eq!(rows(&GlyphVariant::Rotate180.apply(&img)), [[8, 7], [6, 5], [4, 3], [2, 1]]);
+        assert_eq!(rows(&GlyphVariant::FlipHorizontal.apply(&img)), [[2, 1], [4, 3], [6, 5], [8, 7]]);
+        // Turned to 4 x 2 ([7, 5, 3, 1] over [8, 6, 4, 2]), then squeezed
+        // to half the width and stretched to twice the height.
+        assert_eq!(rows(&GlyphVariant::Rotate90.apply(&img)), [[5, 1], [5, 1], [6, 2], [6, 2]]);
+    }
+
+    #[test]
+    fn names() {
+        for variant in VARIANTS {
+            let json = serde_json::to_string(&variant).unwrap();
+            assert_eq!(json, format!("\"{}\"", variant.name()));
+            assert_eq!(serde_json::from_str::<GlyphVariant>(&json).unwrap(), variant);
+        }
+    }
+}
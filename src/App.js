@@ -7,6 +7,8 @@ import ColorPickerQueue from './components/ColorPickerQueue.js';
 
 const App = () => {
   const [imageData, fontData] = useState(null);
+  // { background, foreground } hex colours suggested for the loaded image.
+  const [palette, setPalette] = useState(null);
 
   const handleImageLoaded = (data) => {
     //setImageData(data);
@@ -26,11 +28,11 @@ const App = () => {
         <dir>
             <Container className="mt-5">
 
-                <ImageLoader onImageLoaded={handleImageLoaded} />
+                <ImageLoader onImageLoaded={handleImageLoaded} onPaletteExtracted={setPalette} />
             </Container>
 
             <Container className="mt-5">
-                <FontLoader onFontLoaded={handleFontLoaded} />
+                <FontLoader onFontLoaded={handleFontLoaded} palette={palette} />
 
             </Container>
 
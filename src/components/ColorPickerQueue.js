@@ -1,12 +1,25 @@
-import React, { useState } from 'react';
+import React, { useEffect, useState } from 'react';
 import { Button, ListGroup, ListGroupItem, Row, Col } from 'react-bootstrap';
 import { SketchPicker } from 'react-color';
 
-const ColorPickerQueue = ({ title, onColorsChanged }) => {
-    const [colors, setColors] = useState([]);
+// Shared default, so the effect below doesn't see a new array every render.
+const NO_COLORS = [];
+
+// initialColors prefills the queue, e.g. with the hex colours returned by
+// the wasm extract_image_palette. The queue is replaced whenever a new set
+// arrives, and the parent is told about it like any other edit.
+const ColorPickerQueue = ({ title, onColorsChanged, initialColors = NO_COLORS }) => {
+    const [colors, setColors] = useState(initialColors);
     const [showPicker, setShowPicker] = useState(false);
     const [currentColor, setCurrentColor] = useState('#000000');
 
+    useEffect(() => {
+        setColors(initialColors);
+        onColorsChanged && onColorsChanged(initialColors);
+        // Only a new set of initial colours should reset the queue.
+        // eslint-disable-next-line react-hooks/exhaustive-deps
+    }, [initialColors]);
+
     const handleColorChange = (color) => {
         setCurrentColor(color.hex);
     };
@@ -81,6 +81,8 @@ class FontLoader extends Component {
 
     render() {
         const { fontName, fontFile, error } = this.state;
+        // Colours extracted from the image, see ImageLoader.
+        const { palette } = this.props;
 
         return (
             <div>
@@ -114,12 +116,14 @@ class FontLoader extends Component {
                             <ColorPickerQueue
                                 title="Font Foreground Colors"
                                 onColorsChanged={this.handleFontForegroundColorsChanged}
+                                initialColors={palette ? palette.foreground : undefined}
                             />
                         </Col>
                         <Col>
                             <ColorPickerQueue
                                 title="Font Background Colors"
                                 onColorsChanged={this.handleFontBackgroundColorsChanged}
+                                initialColors={palette ? palette.background : undefined}
                             />
                         </Col>
                     </Row>
@@ -1,4 +1,5 @@
 import HistogramWorkerHandler from './HistogramWorkerHandler';
+import init, { extract_image_palette } from './pkg/ASCII_Renderer.js';
 
 import React, { Component } from 'react';
 import { FormGroup } from 'react-bootstrap';
@@ -71,6 +72,7 @@ class ImageLoader extends Component {
             });
 
             this.loadImage(file);
+            this.extractPalette(file);
             this.props.onImageLoaded && this.props.onImageLoaded(file);
         } else {
             this.setState({ error: 'Please select an image file' });
@@ -109,6 +111,19 @@ class ImageLoader extends Component {
         reader.readAsDataURL(file);
     }
 
+    // Suggests background / foreground colours for the glyphs from the image.
+    extractPalette = async (file) => {
+        try {
+            const buffer = await file.arrayBuffer();
+            await init();
+            const palette = extract_image_palette(new Uint8Array(buffer), 5, 'k-means');
+            this.props.onPaletteExtracted && this.props.onPaletteExtracted(palette);
+        } catch (error) {
+            this.setState({ error: 'Error extracting a palette from the image.' });
+            console.error('Error in extract_image_palette:', error);
+        }
+    };
+
     // Make sure to terminate the worker when the component unmounts
     componentWillUnmount() {
         if (this.histogramWorkerHandler) {